@@ -0,0 +1,62 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use stone_kv::kv::{BlockArena, BytewiseComparator, Skiplist};
+
+const KEYS: u64 = 1_000_000;
+const VALUE: &[u8] = &[0; 16];
+
+fn new_skiplist() -> Skiplist<BytewiseComparator, BlockArena> {
+    Skiplist::new(BytewiseComparator::default(), BlockArena::default())
+}
+
+fn sequential_keys() -> Vec<[u8; 8]> {
+    (0..KEYS).map(|i| i.to_be_bytes()).collect()
+}
+
+fn random_keys() -> Vec<[u8; 8]> {
+    let mut keys = sequential_keys();
+    keys.shuffle(&mut rand::thread_rng());
+    keys
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(KEYS));
+    for (name, keys) in [("sequential", sequential_keys()), ("random", random_keys())] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                new_skiplist,
+                |skiplist| {
+                    for key in &keys {
+                        skiplist.insert(key, VALUE);
+                    }
+                    skiplist
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn get(c: &mut Criterion) {
+    let skiplist = new_skiplist();
+    for key in random_keys() {
+        skiplist.insert(&key, VALUE);
+    }
+    let mut rng = rand::thread_rng();
+    c.bench_function("get", |b| {
+        b.iter(|| {
+            let key = rng.gen_range(0..KEYS).to_be_bytes();
+            let node = skiplist.get(black_box(&key));
+            assert!(!node.is_null());
+            node
+        })
+    });
+}
+
+criterion_group!(benches, insert, get);
+criterion_main!(benches);
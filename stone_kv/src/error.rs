@@ -0,0 +1,27 @@
+use std::fmt::{self, Display};
+
+/// Errors that callers may need to tell apart. They are returned wrapped in `anyhow::Error`,
+/// so match on them with `err.downcast_ref::<Error>()`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// Stored data failed an integrity check.
+    Corrupt(String),
+    /// A write conflicted with existing data: a transaction tried to write a key written by a
+    /// concurrent transaction, in which case retrying it may succeed, or a key was set again in
+    /// an append-only store.
+    Conflict,
+    /// A key that was required to exist was not found.
+    NotFound(Vec<u8>),
+}
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Corrupt(msg) => write!(f, "Corrupt data: {}", msg),
+            Error::Conflict => write!(f, "Serialization conflict"),
+            Error::NotFound(key) => write!(f, "Key not found: {:?}", key),
+        }
+    }
+}
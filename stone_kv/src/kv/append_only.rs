@@ -0,0 +1,96 @@
+use anyhow::{anyhow, Result};
+
+use super::{Keys, Range, Scan, Store};
+use crate::error::Error;
+
+/// A store wrapper for event logs and audit trails, where entries are never changed once
+/// written. Setting an existing key fails with `Error::Conflict`, and deletes are rejected, so
+/// callers have to append new versions under new keys instead.
+pub struct AppendOnlyStore<S: Store> {
+    inner: S,
+}
+
+impl<S: Store> AppendOnlyStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S: Store> Store for AppendOnlyStore<S> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.inner.contains_key(key)
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn scan(&self, range: Range) -> Scan {
+        self.inner.scan(range)
+    }
+
+    fn scan_keys(&self, range: Range) -> Keys {
+        self.inner.scan_keys(range)
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        match self.inner.set_if_absent(key, value)? {
+            true => Ok(()),
+            false => Err(Error::Conflict.into()),
+        }
+    }
+
+    fn set_if_absent(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.inner.set_if_absent(key, value)
+    }
+
+    fn delete(&mut self, _key: &[u8]) -> Result<()> {
+        Err(anyhow!("Cannot delete from an append-only store"))
+    }
+
+    fn range_delete(&mut self, _range: Range) -> Result<u64> {
+        Err(anyhow!("Cannot delete from an append-only store"))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn close(self: Box<Self>) -> Result<()> {
+        Box::new(self.inner).close()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::memory::Memory;
+    use super::super::WriteOp;
+    use super::*;
+
+    #[test]
+    fn test_append_only() -> Result<()> {
+        let mem = Memory::new();
+        let mut store = AppendOnlyStore::new(mem.share());
+        store.set(b"event/1", b"created")?;
+        store.set(b"event/2", b"updated")?;
+
+        let err = store.set(b"event/1", b"deleted").unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::Conflict));
+        assert!(!store.set_if_absent(b"event/1", b"deleted")?);
+        assert!(store.delete(b"event/1").is_err());
+        assert!(store.range_delete(Range::from(..)).is_err());
+        assert!(store
+            .apply_diff(vec![WriteOp::Delete(b"event/2".to_vec())])
+            .is_err());
+
+        assert_eq!(store.get(b"event/1")?, Some(b"created".to_vec()));
+        assert_eq!(mem.len()?, 2);
+        store.set_owned(b"event/3".to_vec(), b"deleted".to_vec())?;
+        assert_eq!(store.scan(Range::from(..)).count(), 3);
+        Ok(())
+    }
+}
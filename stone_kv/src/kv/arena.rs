@@ -1,8 +1,10 @@
-use std::mem;
+use std::collections::{BTreeMap, HashMap};
 use std::ptr;
 use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
+use anyhow::{anyhow, Result};
+
 use super::BLOCK_SIZE;
 
 pub trait Arena: Send + Sync {
@@ -17,22 +19,123 @@ pub struct BlockArena {
     bytes_remaining: AtomicUsize,
     blocks: Arc<Mutex<Vec<Vec<u8>>>>,
     memory_usage: AtomicUsize,
+    /// Freed regions by size, when enabled. Pointers are kept as addresses so the arena
+    /// stays Send and Sync.
+    free_list: Option<Mutex<HashMap<usize, Vec<usize>>>>,
 }
 
 impl BlockArena {
-    fn allocate_fallback(&self, size: usize) -> *mut u8 {
-        if size > BLOCK_SIZE / 4 {
-            return self.allocate_new_block(size);
+    /// Creates an arena that keeps regions handed back through `free` and reuses them for
+    /// later allocations of the same size, so a long-lived arena with churning contents
+    /// doesn't keep growing.
+    pub fn with_free_list() -> Self {
+        Self {
+            free_list: Some(Mutex::default()),
+            ..Default::default()
+        }
+    }
+
+    /// Creates an arena with `blocks` blocks of `BLOCK_SIZE` allocated up front, as a single
+    /// region that allocations are served from before any new block is allocated, so that a
+    /// burst of writes to a new arena doesn't stall on allocation.
+    pub fn with_preallocated(blocks: usize) -> Self {
+        let arena = Self::default();
+        if blocks > 0 {
+            let size = blocks * BLOCK_SIZE;
+            let p = arena.allocate_new_block(size);
+            arena.ptr.store(p, Ordering::Release);
+            arena.bytes_remaining.store(size, Ordering::Release);
+        }
+        arena
+    }
+
+    /// Hands a region back for reuse by a later allocation of the same size. Does nothing
+    /// unless the arena was created with `with_free_list`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated from this arena with exactly `size` bytes, and must not
+    /// be used again after it's freed.
+    pub unsafe fn free(&self, ptr: *mut u8, size: usize) {
+        if let Some(free_list) = &self.free_list {
+            free_list
+                .lock()
+                .unwrap()
+                .entry(size)
+                .or_default()
+                .push(ptr as usize);
+        }
+    }
+
+    /// Takes a freed region of the given size and alignment, if there is one.
+    fn reuse(&self, size: usize, align: usize) -> Option<*mut u8> {
+        let mut free_list = self.free_list.as_ref()?.lock().unwrap();
+        let regions = free_list.get_mut(&size)?;
+        let i = regions.iter().position(|p| p & (align - 1) == 0)?;
+        Some(regions.swap_remove(i) as *mut u8)
+    }
+
+    /// Like `allocate`, but reports a zero-sized chunk, an alignment that isn't a power of two
+    /// or a misaligned result as an error rather than panicking.
+    pub fn try_allocate<T>(&self, chunk: usize, align: usize) -> Result<*mut T> {
+        if chunk == 0 {
+            return Err(anyhow!("Cannot allocate an empty chunk"));
+        }
+        if !align.is_power_of_two() {
+            return Err(anyhow!("Alignment {} is not a power of two", align));
+        }
+        let result = unsafe { self.allocate_aligned(chunk, align) };
+        if result as usize & (align - 1) != 0 {
+            return Err(anyhow!("Allocated memory is not aligned with {}", align));
+        }
+        Ok(result as *mut T)
+    }
+
+    /// Allocates `chunk` bytes aligned with `align`, which must be a power of two.
+    unsafe fn allocate_aligned(&self, chunk: usize, align: usize) -> *mut u8 {
+        if let Some(p) = self.reuse(chunk, align) {
+            return p;
+        }
+        let slop = Self::slop(self.ptr.load(Ordering::Acquire), align);
+        let needed = chunk + slop;
+        if needed <= self.bytes_remaining.load(Ordering::Acquire) {
+            let p = self.ptr.load(Ordering::Acquire).add(slop);
+            self.ptr.store(p.add(chunk), Ordering::Release);
+            self.bytes_remaining.fetch_sub(needed, Ordering::SeqCst);
+            p
+        } else {
+            self.allocate_fallback(chunk, align)
+        }
+    }
+
+    /// Returns the padding needed to align `ptr` with `align`.
+    fn slop(ptr: *mut u8, align: usize) -> usize {
+        let current_mod = ptr as usize & (align - 1);
+        if current_mod == 0 {
+            0
+        } else {
+            align - current_mod
+        }
+    }
+
+    fn allocate_fallback(&self, size: usize, align: usize) -> *mut u8 {
+        // New blocks are only as aligned as the allocator makes them, so leave room to align
+        // within the block.
+        if size + align - 1 > BLOCK_SIZE / 4 {
+            let p = self.allocate_new_block(size + align - 1);
+            return unsafe { p.add(Self::slop(p, align)) };
         }
 
         let new_block_ptr = self.allocate_new_block(BLOCK_SIZE);
+        let slop = Self::slop(new_block_ptr, align);
+        let result = unsafe { new_block_ptr.add(slop) };
         unsafe {
-            let ptr = new_block_ptr.add(size);
+            let ptr = result.add(size);
             self.ptr.store(ptr, Ordering::Release);
         };
         self.bytes_remaining
-            .store(BLOCK_SIZE - size, Ordering::Release);
-        new_block_ptr
+            .store(BLOCK_SIZE - size - slop, Ordering::Release);
+        result
     }
 
     fn allocate_new_block(&self, block_bytes: usize) -> *mut u8 {
@@ -48,32 +151,14 @@ impl BlockArena {
 impl Arena for BlockArena {
     unsafe fn allocate<T>(&self, chunk: usize, align: usize) -> *mut T {
         assert!(chunk > 0);
-        let ptr_size = mem::size_of::<usize>();
-
         assert_eq!(align & (align - 1), 0);
 
-        let slop = {
-            let current_mod = self.ptr.load(Ordering::Acquire) as usize & (align - 1);
-            if current_mod == 0 {
-                0
-            } else {
-                align - current_mod
-            }
-        };
-        let needed = chunk + slop;
-        let result = if needed <= self.bytes_remaining.load(Ordering::Acquire) {
-            let p = self.ptr.load(Ordering::Acquire).add(slop);
-            self.ptr.store(p.add(chunk), Ordering::Release);
-            self.bytes_remaining.fetch_sub(needed, Ordering::SeqCst);
-            p
-        } else {
-            self.allocate_fallback(chunk)
-        };
+        let result = self.allocate_aligned(chunk, align);
         assert_eq!(
             result as usize & (align - 1),
             0,
             "allocated memory should be aligned with {}",
-            ptr_size
+            align
         );
         result as *mut T
     }
@@ -84,6 +169,35 @@ impl Arena for BlockArena {
     }
 }
 
+/// An append-only store for large values kept out of line. Each blob is addressed by its
+/// offset in the arena, which stays stable as more blobs are added.
+#[derive(Default)]
+pub struct BlobArena {
+    blobs: Mutex<BTreeMap<u64, Vec<u8>>>,
+    memory_usage: AtomicUsize,
+}
+
+impl BlobArena {
+    /// Stores a blob, returning its offset.
+    pub fn put(&self, blob: &[u8]) -> u64 {
+        let mut blobs = self.blobs.lock().unwrap();
+        let offset = self.memory_usage.fetch_add(blob.len(), Ordering::Relaxed) as u64;
+        blobs.insert(offset, blob.to_vec());
+        offset
+    }
+
+    /// Fetches the blob at the given offset, if one of the given length is there.
+    pub fn get(&self, offset: u64, len: usize) -> Option<Vec<u8>> {
+        let blobs = self.blobs.lock().unwrap();
+        blobs.get(&offset).filter(|blob| blob.len() == len).cloned()
+    }
+
+    #[inline]
+    pub fn memory_used(&self) -> usize {
+        self.memory_usage.load(Ordering::Acquire)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -112,4 +226,69 @@ mod test {
             )
         }
     }
+
+    #[test]
+    fn test_preallocated() {
+        let a = BlockArena::with_preallocated(4);
+        assert_eq!(a.memory_used(), 4 * BLOCK_SIZE);
+        assert_eq!(a.blocks.lock().unwrap().len(), 1);
+
+        // Allocations fit in the preallocated region, large ones included.
+        let mut allocated = 0;
+        for size in [8, 100, BLOCK_SIZE / 2, BLOCK_SIZE, 7] {
+            let p = a.try_allocate::<u8>(size, 8).unwrap();
+            assert_eq!(p as usize % 8, 0);
+            allocated += size;
+        }
+        assert!(allocated < 4 * BLOCK_SIZE);
+        assert_eq!(a.memory_used(), 4 * BLOCK_SIZE);
+        assert_eq!(a.blocks.lock().unwrap().len(), 1);
+
+        // Once it runs out, blocks are allocated as usual.
+        a.try_allocate::<u8>(3 * BLOCK_SIZE, 8).unwrap();
+        assert!(a.memory_used() > 4 * BLOCK_SIZE);
+
+        let a = BlockArena::with_preallocated(0);
+        assert_eq!(a.memory_used(), 0);
+    }
+
+    #[test]
+    fn test_try_allocate() {
+        let a = BlockArena::default();
+        for align in [1, 8, 64] {
+            // Odd sizes so the next allocation starts misaligned, plus one too large for a
+            // shared block.
+            for size in [1, 3, 17, BLOCK_SIZE / 2] {
+                let p = a.try_allocate::<u8>(size, align).unwrap();
+                assert_eq!(
+                    p as usize % align,
+                    0,
+                    "{} bytes aligned with {}",
+                    size,
+                    align
+                );
+            }
+        }
+        assert!(a.try_allocate::<u8>(8, 3).is_err());
+        assert!(a.try_allocate::<u8>(8, 0).is_err());
+        assert!(a.try_allocate::<u8>(0, 8).is_err());
+    }
+
+    #[test]
+    fn test_free_list() {
+        let a = BlockArena::with_free_list();
+        let size = BLOCK_SIZE;
+        let p = a.try_allocate::<u8>(size, 8).unwrap();
+        let used = a.memory_used();
+        unsafe { a.free(p, size) };
+        assert_eq!(a.try_allocate::<u8>(size, 8).unwrap(), p);
+        assert_eq!(a.memory_used(), used);
+
+        // Without a free list, freed regions aren't reused.
+        let a = BlockArena::default();
+        let p = a.try_allocate::<u8>(size, 8).unwrap();
+        unsafe { a.free(p, size) };
+        assert_ne!(a.try_allocate::<u8>(size, 8).unwrap(), p);
+        assert!(a.memory_used() > used);
+    }
 }
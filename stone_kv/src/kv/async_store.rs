@@ -0,0 +1,240 @@
+use std::ops::Bound;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+use tokio::task::spawn_blocking;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+
+use super::{Range, Store};
+
+/// The number of scanned entries read per lock acquisition, and buffered ahead of the stream
+/// consumer.
+const SCAN_BUFFER: usize = 64;
+
+pub type ScanStream = Pin<Box<dyn Stream<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>>;
+
+/// An async counterpart of `Store`, for use from the async RPC layer.
+#[async_trait]
+pub trait AsyncStore: Send + Sync {
+    /// Gets a value for a key, if it exists.
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Streams an ordered range of key/value pairs. The stream may see writes made while it's
+    /// being consumed.
+    fn scan(&self, range: Range) -> ScanStream;
+
+    /// Sets a value for a key, replacing the existing value if any.
+    async fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    /// Deletes a key, or does nothing if it does not exist.
+    async fn delete(&mut self, key: &[u8]) -> Result<()>;
+
+    /// Flushes any buffered data to the underlying storage medium, without blocking the
+    /// executor while it waits for the disk.
+    async fn flush_async(&mut self) -> Result<()>;
+}
+
+/// Adapts a sync `Store` to `AsyncStore` by running its calls on tokio's blocking thread pool,
+/// so they never block the executor.
+pub struct AsyncStoreAdapter<S: Store> {
+    store: Arc<RwLock<S>>,
+    /// See `Store::flush_is_noop`. Checked up front, since taking the lock to ask could block.
+    flush_is_noop: bool,
+}
+
+impl<S: Store> Clone for AsyncStoreAdapter<S> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            flush_is_noop: self.flush_is_noop,
+        }
+    }
+}
+
+impl<S: Store + 'static> AsyncStoreAdapter<S> {
+    pub fn new(store: S) -> Self {
+        let flush_is_noop = store.flush_is_noop();
+        Self {
+            store: Arc::new(RwLock::new(store)),
+            flush_is_noop,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Store + 'static> AsyncStore for AsyncStoreAdapter<S> {
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let store = self.store.clone();
+        let key = key.to_vec();
+        spawn_blocking(move || store.read().unwrap().get(&key)).await?
+    }
+
+    fn scan(&self, range: Range) -> ScanStream {
+        // Scan iterators aren't Send, so drive the scan on a blocking thread and hand entries
+        // over through a bounded channel, which also applies backpressure to the scan. The
+        // store is read in batches, releasing the lock before sending each, so that a consumer
+        // holding a partly read stream while it writes to the store doesn't deadlock.
+        let store = self.store.clone();
+        let (tx, rx) = mpsc::channel(SCAN_BUFFER);
+        spawn_blocking(move || {
+            let mut range = range;
+            loop {
+                let batch = store
+                    .read()
+                    .unwrap()
+                    .scan(range.clone())
+                    .take(SCAN_BUFFER)
+                    .collect::<Vec<_>>();
+                let resume_after = match batch.last() {
+                    Some(Ok((key, _))) if batch.len() == SCAN_BUFFER => Some(key.clone()),
+                    _ => None,
+                };
+                for item in batch {
+                    let failed = item.is_err();
+                    if tx.blocking_send(item).is_err() || failed {
+                        return;
+                    }
+                }
+                match resume_after {
+                    Some(key) => range.start = Bound::Excluded(key),
+                    None => return,
+                }
+            }
+        });
+        Box::pin(ReceiverStream::new(rx))
+    }
+
+    async fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let store = self.store.clone();
+        let (key, value) = (key.to_vec(), value.to_vec());
+        spawn_blocking(move || store.write().unwrap().set(&key, &value)).await?
+    }
+
+    async fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let store = self.store.clone();
+        let key = key.to_vec();
+        spawn_blocking(move || store.write().unwrap().delete(&key)).await?
+    }
+
+    async fn flush_async(&mut self) -> Result<()> {
+        if self.flush_is_noop {
+            return Ok(());
+        }
+        let store = self.store.clone();
+        spawn_blocking(move || store.write().unwrap().flush()).await?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::memory::Memory;
+    use super::super::{Keys, Scan};
+    use super::*;
+    use std::fs::File;
+    use std::path::PathBuf;
+    use tokio_stream::StreamExt;
+
+    /// A store that persists a dump of its contents to a file on flush.
+    struct FileStore {
+        inner: Memory,
+        path: PathBuf,
+    }
+
+    impl Store for FileStore {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            self.inner.get(key)
+        }
+
+        fn scan(&self, range: Range) -> Scan {
+            self.inner.scan(range)
+        }
+
+        fn scan_keys(&self, range: Range) -> Keys {
+            self.inner.scan_keys(range)
+        }
+
+        fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+            self.inner.set(key, value)
+        }
+
+        fn delete(&mut self, key: &[u8]) -> Result<()> {
+            self.inner.delete(key)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            let mut file = File::create(&self.path)?;
+            self.inner.dump(&mut file)?;
+            file.sync_all()?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_adapter() -> Result<()> {
+        let mut store = AsyncStoreAdapter::new(Memory::new());
+        for i in 0..10 {
+            store.set(&[i], &[i * 2]).await?;
+        }
+        store.delete(&[5]).await?;
+        assert_eq!(store.get(&[4]).await?, Some(vec![8]));
+        assert_eq!(store.get(&[5]).await?, None);
+
+        let scan = store
+            .scan(Range::from(vec![3]..vec![7]))
+            .collect::<Result<Vec<_>>>()
+            .await?;
+        assert_eq!(
+            scan,
+            vec![(vec![3], vec![6]), (vec![4], vec![8]), (vec![6], vec![12])]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_during_scan() -> Result<()> {
+        let mut store = AsyncStoreAdapter::new(Memory::new());
+        for i in 0..=255 {
+            store.set(&[i], &[i]).await?;
+        }
+        let mut scan = store.scan(Range::from(..));
+        assert_eq!(scan.next().await.transpose()?, Some((vec![0], vec![0])));
+
+        // The partly read scan doesn't hold the lock while waiting for us.
+        let set = store.set(&[0], b"x");
+        tokio::time::timeout(std::time::Duration::from_secs(10), set).await??;
+        let keys = scan
+            .map(|item| item.map(|(k, _)| k[0]))
+            .collect::<Result<Vec<_>>>()
+            .await?;
+        assert_eq!(keys, (1..=255).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flush_async() -> Result<()> {
+        let mut store = AsyncStoreAdapter::new(Memory::new());
+        store.set(b"a", b"1").await?;
+        store.flush_async().await?;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("store");
+        let file_store = FileStore {
+            inner: Memory::new(),
+            path: path.clone(),
+        };
+        let mut store = AsyncStoreAdapter::new(file_store);
+        for i in 0..10 {
+            store.set(&[i], &[i * 2]).await?;
+        }
+        store.flush_async().await?;
+
+        let mut reopened = Memory::new();
+        assert_eq!(reopened.load(&mut File::open(&path)?)?, 10);
+        assert_eq!(reopened.get(&[4])?, Some(vec![8]));
+        Ok(())
+    }
+}
@@ -6,6 +6,14 @@ pub trait Comparator: Send + Sync + Clone + Default {
     fn name(&self) -> &str;
 
     fn successor(&self, key: &[u8]) -> Vec<u8>;
+
+    /// Returns the smallest key strictly greater than `key`, unlike `successor` which may
+    /// return a shorter separator. Useful for turning an inclusive range end into an exclusive one.
+    fn successor_exact(&self, key: &[u8]) -> Vec<u8> {
+        let mut res = key.to_vec();
+        res.push(0x00);
+        res
+    }
 }
 
 #[derive(Default, Clone, Copy)]
@@ -36,3 +44,24 @@ impl Comparator for BytewiseComparator {
         key.to_owned()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_successor() {
+        let c = BytewiseComparator::default();
+        assert_eq!(c.successor(&[1, 0xff]), vec![2]);
+        assert_eq!(c.successor_exact(&[1, 0xff]), vec![1, 0xff, 0x00]);
+        assert_eq!(
+            c.compare(&c.successor_exact(&[1, 0xff]), &[1, 0xff]),
+            Ordering::Greater
+        );
+        assert_eq!(
+            c.compare(&c.successor_exact(&[1, 0xff]), &[1, 0xff, 0x00, 0x00]),
+            Ordering::Less
+        );
+        assert_eq!(c.successor_exact(&[]), vec![0x00]);
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use super::{Keys, Range, Scan, Store};
+use crate::error::Error;
+
+const NONCE_SIZE: usize = 12;
+
+/// A store wrapper that encrypts values at rest. Keys are stored in plaintext so that ordering
+/// and range scans keep working; each value is sealed with a random nonce prepended to it.
+pub struct EncryptedStore<S: Store> {
+    inner: S,
+    cipher: ChaCha20Poly1305,
+}
+
+impl<S: Store> EncryptedStore<S> {
+    pub fn new(inner: S, key: &[u8; 32]) -> Self {
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    fn encrypt(&self, value: &[u8]) -> Result<Vec<u8>> {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(Nonce::from_slice(&nonce), value)
+                .map_err(|_| anyhow!("Failed to encrypt value"))?,
+        );
+        Ok(sealed)
+    }
+}
+
+fn decrypt(cipher: &ChaCha20Poly1305, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_SIZE {
+        return Err(Error::Corrupt("encrypted value is truncated".into()).into());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Error::Corrupt("encrypted value failed authentication".into()).into())
+}
+
+impl<S: Store> Store for EncryptedStore<S> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner
+            .get(key)?
+            .map(|sealed| decrypt(&self.cipher, &sealed))
+            .transpose()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.inner.contains_key(key)
+    }
+
+    fn scan(&self, range: Range) -> Scan {
+        let cipher = self.cipher.clone();
+        Box::new(self.inner.scan(range).map(move |item| {
+            let (key, sealed) = item?;
+            Ok((key, decrypt(&cipher, &sealed)?))
+        }))
+    }
+
+    fn scan_keys(&self, range: Range) -> Keys {
+        self.inner.scan_keys(range)
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let sealed = self.encrypt(value)?;
+        self.inner.set(key, &sealed)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.inner.delete(key)
+    }
+
+    fn range_delete(&mut self, range: Range) -> Result<u64> {
+        self.inner.range_delete(range)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn close(self: Box<Self>) -> Result<()> {
+        Box::new(self.inner).close()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::memory::Memory;
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let mut store = EncryptedStore::new(Memory::new(), &[7; 32]);
+        store.set(b"a", b"secret")?;
+        store.set(b"b", b"other")?;
+        assert_eq!(store.get(b"a")?, Some(b"secret".to_vec()));
+        assert_eq!(store.get(b"c")?, None);
+
+        let sealed = store.inner.get(b"a")?.unwrap();
+        assert_ne!(&sealed[NONCE_SIZE..], b"secret");
+
+        let scan = store.scan(Range::from(..)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            scan,
+            vec![
+                (b"a".to_vec(), b"secret".to_vec()),
+                (b"b".to_vec(), b"other".to_vec())
+            ]
+        );
+        store.delete(b"a")?;
+        assert_eq!(store.get(b"a")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tampered_value() -> Result<()> {
+        let mut store = EncryptedStore::new(Memory::new(), &[7; 32]);
+        store.set(b"a", b"secret")?;
+        let mut sealed = store.inner.get(b"a")?.unwrap();
+        *sealed.last_mut().unwrap() ^= 0x01;
+        store.inner.set(b"a", &sealed)?;
+
+        let err = store.get(b"a").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Corrupt(_))
+        ));
+        assert!(store.scan(Range::from(..)).next().unwrap().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::{anyhow, Result};
+
+/// Builds composite keys whose byte order under `BytewiseComparator` matches the order of
+/// their components, so that tuples can be range scanned.
+#[derive(Default)]
+pub struct KeyBuilder {
+    bytes: Vec<u8>,
+}
+
+impl KeyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a u64 in big-endian order, so that integers sort numerically.
+    pub fn push_u64_be(mut self, n: u64) -> Self {
+        self.bytes.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    /// Appends a string, sorting lexicographically. 0x00 bytes are escaped as 0x00 0xff and
+    /// the string is terminated by 0x00 0x00, so that a string sorts before its extensions.
+    pub fn push_str(mut self, s: &str) -> Self {
+        for &b in s.as_bytes() {
+            match b {
+                0x00 => self.bytes.extend_from_slice(&[0x00, 0xff]),
+                b => self.bytes.push(b),
+            }
+        }
+        self.bytes.extend_from_slice(&[0x00, 0x00]);
+        self
+    }
+
+    /// Appends a big-endian u32 length followed by the raw bytes. These sort by length first,
+    /// so use `push_str` where lexicographic order matters.
+    pub fn push_bytes(mut self, bytes: &[u8]) -> Self {
+        self.bytes
+            .extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Decodes keys built by `KeyBuilder`, reading components in the order they were pushed.
+pub struct KeyReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> KeyReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn read_u64_be(&mut self) -> Result<u64> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into()?))
+    }
+
+    pub fn read_str(&mut self) -> Result<String> {
+        let mut decoded = Vec::new();
+        let mut iter = self.bytes.iter().enumerate();
+        loop {
+            match iter.next() {
+                Some((_, 0x00)) => match iter.next() {
+                    Some((i, 0x00)) => {
+                        self.bytes = &self.bytes[i + 1..];
+                        return Ok(String::from_utf8(decoded)?);
+                    }
+                    Some((_, 0xff)) => decoded.push(0x00),
+                    _ => return Err(anyhow!("Invalid string escape in key")),
+                },
+                Some((_, b)) => decoded.push(*b),
+                None => return Err(anyhow!("Unterminated string in key")),
+            }
+        }
+    }
+
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>> {
+        let len = u32::from_be_bytes(self.take(4)?.try_into()?);
+        Ok(self.take(len as usize)?.to_vec())
+    }
+
+    /// Returns true if every component has been read.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            return Err(anyhow!("Unexpected end of key"));
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::memory::Memory;
+    use super::super::{Range, Store};
+    use super::*;
+
+    #[test]
+    fn test_tuple_order() -> Result<()> {
+        let mut tuples = vec![
+            (2, "a"),
+            (1, "b"),
+            (256, ""),
+            (1, "ab"),
+            (1, "a\0"),
+            (1, "a"),
+            (0, "zzz"),
+        ];
+        let mut mem = Memory::new();
+        for (n, s) in &tuples {
+            let key = KeyBuilder::new().push_u64_be(*n).push_str(s).build();
+            mem.set(&key, &[])?;
+        }
+
+        tuples.sort();
+        let mut scanned = Vec::new();
+        for item in mem.scan(Range::from(..)) {
+            let (key, _) = item?;
+            let mut reader = KeyReader::new(&key);
+            scanned.push((reader.read_u64_be()?, reader.read_str()?));
+            assert!(reader.is_empty());
+        }
+        let expect = tuples
+            .iter()
+            .map(|(n, s)| (*n, s.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(scanned, expect);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytes() -> Result<()> {
+        let key = KeyBuilder::new()
+            .push_bytes(b"raw\0")
+            .push_u64_be(7)
+            .build();
+        let mut reader = KeyReader::new(&key);
+        assert_eq!(reader.read_bytes()?, b"raw\0");
+        assert_eq!(reader.read_u64_be()?, 7);
+        assert!(reader.is_empty());
+        assert!(reader.read_u64_be().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, TryLockError};
+
+/// The reader-writer lock guarding a skiplist.
+///
+/// Long scans hold the read lock, so the lock's policy decides whether a write-heavy load
+/// starves readers or the other way round. std's lock leaves the policy to the platform. With
+/// the `fair_lock` feature, parking_lot's lock is available instead: it is eventually fair,
+/// handing the lock directly to the longest waiting thread every so often, which bounds how
+/// long either side can be starved at the cost of a little throughput.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LockKind {
+    #[default]
+    Std,
+    #[cfg(feature = "fair_lock")]
+    ParkingLot,
+}
+
+/// A reader-writer lock of a given kind, counting acquisitions that had to block. Neither
+/// kind is reentrant: new readers may queue behind a waiting writer, so a thread that takes
+/// the read lock while already holding it can deadlock.
+///
+/// A thread panicking while holding std's lock poisons it. The lock is taken regardless, so
+/// that one panic doesn't make every later operation on the skiplist panic too: the skiplist
+/// only runs code that can panic, like comparators and the callback of
+/// `get_or_insert_with`, before it starts relinking nodes, so it's still consistent.
+pub struct Lock<T> {
+    inner: Inner<T>,
+    contention: AtomicU64,
+}
+
+enum Inner<T> {
+    Std(std::sync::RwLock<T>),
+    #[cfg(feature = "fair_lock")]
+    ParkingLot(parking_lot::RwLock<T>),
+}
+
+pub enum ReadGuard<'a, T> {
+    Std(std::sync::RwLockReadGuard<'a, T>),
+    #[cfg(feature = "fair_lock")]
+    ParkingLot(parking_lot::RwLockReadGuard<'a, T>),
+}
+
+pub enum WriteGuard<'a, T> {
+    Std(std::sync::RwLockWriteGuard<'a, T>),
+    #[cfg(feature = "fair_lock")]
+    ParkingLot(parking_lot::RwLockWriteGuard<'a, T>),
+}
+
+impl<T> Lock<T> {
+    pub fn new(kind: LockKind, value: T) -> Self {
+        let inner = match kind {
+            LockKind::Std => Inner::Std(std::sync::RwLock::new(value)),
+            #[cfg(feature = "fair_lock")]
+            LockKind::ParkingLot => Inner::ParkingLot(parking_lot::RwLock::new(value)),
+        };
+        Self {
+            inner,
+            contention: AtomicU64::new(0),
+        }
+    }
+
+    /// Acquires a read lock, even if it's poisoned.
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        match &self.inner {
+            Inner::Std(lock) => match lock.try_read() {
+                Ok(guard) => ReadGuard::Std(guard),
+                Err(TryLockError::WouldBlock) => {
+                    self.contended();
+                    ReadGuard::Std(lock.read().unwrap_or_else(PoisonError::into_inner))
+                }
+                Err(TryLockError::Poisoned(err)) => ReadGuard::Std(err.into_inner()),
+            },
+            #[cfg(feature = "fair_lock")]
+            Inner::ParkingLot(lock) => match lock.try_read() {
+                Some(guard) => ReadGuard::ParkingLot(guard),
+                None => {
+                    self.contended();
+                    ReadGuard::ParkingLot(lock.read())
+                }
+            },
+        }
+    }
+
+    /// Acquires a write lock, even if it's poisoned.
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        match &self.inner {
+            Inner::Std(lock) => match lock.try_write() {
+                Ok(guard) => WriteGuard::Std(guard),
+                Err(TryLockError::WouldBlock) => {
+                    self.contended();
+                    WriteGuard::Std(lock.write().unwrap_or_else(PoisonError::into_inner))
+                }
+                Err(TryLockError::Poisoned(err)) => WriteGuard::Std(err.into_inner()),
+            },
+            #[cfg(feature = "fair_lock")]
+            Inner::ParkingLot(lock) => match lock.try_write() {
+                Some(guard) => WriteGuard::ParkingLot(guard),
+                None => {
+                    self.contended();
+                    WriteGuard::ParkingLot(lock.write())
+                }
+            },
+        }
+    }
+
+    /// Returns the number of acquisitions that found the lock held and had to block.
+    pub fn contention_count(&self) -> u64 {
+        self.contention.load(Ordering::Relaxed)
+    }
+
+    fn contended(&self) {
+        self.contention.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<'a, T> Deref for ReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        match self {
+            ReadGuard::Std(guard) => guard,
+            #[cfg(feature = "fair_lock")]
+            ReadGuard::ParkingLot(guard) => guard,
+        }
+    }
+}
+
+impl<'a, T> Deref for WriteGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        match self {
+            WriteGuard::Std(guard) => guard,
+            #[cfg(feature = "fair_lock")]
+            WriteGuard::ParkingLot(guard) => guard,
+        }
+    }
+}
+
+impl<'a, T> DerefMut for WriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self {
+            WriteGuard::Std(guard) => guard,
+            #[cfg(feature = "fair_lock")]
+            WriteGuard::ParkingLot(guard) => guard,
+        }
+    }
+}
@@ -1,100 +1,722 @@
-use anyhow::{Ok, Result};
+use std::any::Any;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::iter::FusedIterator;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Ok, Result};
+
+use crate::error::Error;
 
 use super::arena::*;
 use super::comparator::*;
 use super::skiplist::{Skiplist, Node};
 use super::{Bound, Range, Store};
-use super::Scan;
+use super::{Keys, Scan};
 
-#[derive(Clone)]
+/// An in-memory store backed by a skiplist. It deliberately doesn't implement `Clone`: use
+/// `share` for another handle to the same data, or `deep_copy` for an independent copy.
 pub struct Memory {
     skiplist: Skiplist<BytewiseComparator, BlockArena>,
+    format: ValueFormat,
+    limits: Limits,
+    counters: Arc<Counters>,
+}
+
+/// An entry found by `Memory::find_nearest`, with the ordering of its key relative to the
+/// key searched for.
+pub type Nearest = (Vec<u8>, Vec<u8>, Ordering);
+
+/// Counts of the operations performed on a store.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StoreMetrics {
+    /// Keys looked up by get and multi_get.
+    pub gets: u64,
+    /// Lookups that found no value.
+    pub get_misses: u64,
+    pub sets: u64,
+    pub deletes: u64,
+    /// Scans started, of values or keys.
+    pub scans: u64,
+}
+
+/// A breakdown of the memory a store takes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryUsage {
+    /// Bytes allocated by the skiplist and blob arenas, including space not yet handed out and
+    /// space taken by deleted entries, which arenas never reclaim.
+    pub arena_bytes: usize,
+    /// Bytes of the stored keys and values, including checksums and blob handles. They're
+    /// allocated outside the arena.
+    pub data_bytes: usize,
+    /// Arena bytes taken by the skiplist nodes themselves, i.e. their headers and pointers.
+    pub node_overhead_bytes: usize,
+    pub entry_count: usize,
+}
+
+impl MemoryUsage {
+    /// Returns the total bytes the store holds on to.
+    pub fn total_bytes(&self) -> usize {
+        self.arena_bytes + self.data_bytes
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    gets: AtomicU64,
+    get_misses: AtomicU64,
+    sets: AtomicU64,
+    deletes: AtomicU64,
+    scans: AtomicU64,
 }
 
+/// Length of the CRC32 appended to each stored value when integrity checks are enabled.
+const CHECKSUM_SIZE: usize = 4;
+
+/// Tags the stored value when a blob threshold is set: the value follows inline, or a blob
+/// handle of a big-endian u64 offset and length follows.
+const VALUE_INLINE: u8 = 0x00;
+const VALUE_BLOB: u8 = 0x01;
+
 impl Memory {
     pub fn new() -> Self {
         Self {
             skiplist: Skiplist::new(BytewiseComparator::default(), BlockArena::default()),
+            format: ValueFormat::default(),
+            limits: Limits::default(),
+            counters: Arc::default(),
+        }
+    }
+
+    /// Enables or disables integrity checks. When enabled, a CRC32 over the key and value is
+    /// stored with every value on `set` and verified on reads, which return `Error::Corrupt` on
+    /// a mismatch. It must be chosen before any data is written.
+    pub fn with_integrity_checks(mut self, enabled: bool) -> Self {
+        self.format.integrity_checks = enabled;
+        self
+    }
+
+    /// Stores values longer than `bytes` in a separate blob arena, leaving only a small handle
+    /// in the skiplist node so that large values don't bloat nodes or slow down key traversal.
+    /// Smaller values stay inline. It must be chosen before any data is written.
+    pub fn with_blob_threshold(mut self, bytes: usize) -> Self {
+        self.format.blob_threshold = Some(bytes);
+        self
+    }
+
+    /// Rejects writes of keys longer than `max_key_len` bytes or values longer than
+    /// `max_value_len` bytes with an error, before anything is stored. This catches bugs like a
+    /// serialized struct accidentally used as a key. By default there are no limits.
+    pub fn with_limits(mut self, max_key_len: usize, max_value_len: usize) -> Self {
+        self.limits = Limits {
+            max_key_len: Some(max_key_len),
+            max_value_len: Some(max_value_len),
+        };
+        self
+    }
+
+    /// Returns the operation counts since the store was created, across all shared handles.
+    pub fn metrics(&self) -> StoreMetrics {
+        StoreMetrics {
+            gets: self.counters.gets.load(AtomicOrdering::Relaxed),
+            get_misses: self.counters.get_misses.load(AtomicOrdering::Relaxed),
+            sets: self.counters.sets.load(AtomicOrdering::Relaxed),
+            deletes: self.counters.deletes.load(AtomicOrdering::Relaxed),
+            scans: self.counters.scans.load(AtomicOrdering::Relaxed),
         }
     }
+
+    /// Returns the memory the store takes, which is a better basis for flush decisions than
+    /// the entry count.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            arena_bytes: self.skiplist.memory_used() + self.format.blobs.memory_used(),
+            data_bytes: self.skiplist.data_size(),
+            node_overhead_bytes: self.skiplist.node_size(),
+            entry_count: self.skiplist.count(),
+        }
+    }
+
+    /// Returns another handle to the same underlying data. Writes through either handle are
+    /// visible through both.
+    pub fn share(&self) -> Self {
+        Self {
+            skiplist: self.skiplist.clone(),
+            format: self.format.clone(),
+            limits: self.limits,
+            counters: self.counters.clone(),
+        }
+    }
+
+    /// Returns an independent copy of the store's current contents.
+    pub fn deep_copy(&self) -> Result<Self> {
+        let mut copy = Self::new();
+        copy.format.integrity_checks = self.format.integrity_checks;
+        copy.format.blob_threshold = self.format.blob_threshold;
+        copy.limits = self.limits;
+        for item in self.scan(Range::from(..)) {
+            let (key, value) = item?;
+            copy.set(&key, &value)?;
+        }
+        Ok(copy)
+    }
+
+    /// Finds the key closest to the given key, preferring the first key greater than or equal
+    /// to it and otherwise the last key. Returns the entry along with the ordering of its key
+    /// relative to the given key, or None if the store is empty.
+    pub fn find_nearest(&self, key: &[u8]) -> Result<Option<Nearest>> {
+        let node = self.skiplist.get_greater_or_equal(key);
+        let node = match self.skiplist.is_tail(node) {
+            true => self.skiplist.get_less_or_equal(key),
+            false => node,
+        };
+        if self.skiplist.is_head(node) {
+            return Ok(None);
+        }
+        let (k, v) = unsafe { self.skiplist.read_node(node, Node::get_key_value) };
+        let v = self.format.decode(&k, v)?;
+        let ordering = k.as_slice().cmp(key);
+        Ok(Some((k, v, ordering)))
+    }
+
+    /// Checks the skiplist's internal consistency without modifying it, returning
+    /// `Error::Corrupt` describing the first violation found.
+    pub fn verify(&self) -> Result<()> {
+        self.skiplist
+            .verify()
+            .map_err(|msg| Error::Corrupt(format!("skiplist: {}", msg)).into())
+    }
+
+    /// Calls `f` with every key/value pair in the range, in order, under a single read lock.
+    /// Keys and values are borrowed straight from the skiplist without copying, except for
+    /// values that need decoding because of integrity checks or a blob threshold. Stops at the
+    /// first error. `f` must not use the store at all, since the lock is held throughout: a
+    /// write deadlocks, and so can a read if a writer is queued behind a fair lock.
+    pub fn for_each(
+        &self,
+        range: Range,
+        mut f: impl FnMut(&[u8], &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        self.counters.scans.fetch_add(1, AtomicOrdering::Relaxed);
+        if self.format.is_raw() {
+            return self.skiplist.for_each(&range, f);
+        }
+        self.skiplist.for_each(&range, |key, value| {
+            let value = self.format.decode(key, value.to_vec())?;
+            f(key, &value)
+        })
+    }
+
+    /// Scans the range, yielding entries until `pred` returns false for a key and then
+    /// stopping, without looking at any further entries. An error also ends the scan.
+    pub fn scan_while<F>(&self, range: Range, pred: F) -> Scan
+    where
+        F: Fn(&[u8]) -> bool + 'static,
+    {
+        Box::new(ScanWhile {
+            inner: self.scan(range),
+            pred,
+            done: false,
+            tail: None,
+        })
+    }
+
+    /// Splits a range into `partitions` contiguous, disjoint sub-ranges covering all of it, in
+    /// key order. The split keys are sampled as for `estimate_count`, so the sub-ranges only
+    /// hold roughly equal numbers of keys, and some may be empty if the range has too few keys
+    /// to split. Unlike scans, ranges can be sent to other threads to scan with `share`d handles.
+    pub fn split_range(&self, range: Range, partitions: usize) -> Vec<Range> {
+        let partitions = partitions.max(1);
+        let mut ranges = Vec::with_capacity(partitions);
+        let mut start = range.start.clone();
+        for key in self.skiplist.split_keys(&range, partitions) {
+            ranges.push(Range {
+                start,
+                end: Bound::Excluded(key.clone()),
+            });
+            start = Bound::Included(key);
+        }
+        ranges.push(Range {
+            start,
+            end: range.end,
+        });
+        // Pad with empty ranges, which are excluded at both ends of the last key.
+        while ranges.len() < partitions {
+            let key = match &ranges[ranges.len() - 1].end {
+                Bound::Included(key) | Bound::Excluded(key) => key.clone(),
+                Bound::Unbounded => Vec::new(),
+            };
+            ranges.push(Range {
+                start: Bound::Excluded(key.clone()),
+                end: Bound::Excluded(key),
+            });
+        }
+        ranges
+    }
+
+    /// Splits a range into `partitions` scans of the sub-ranges from `split_range`.
+    pub fn par_scan(&self, range: Range, partitions: usize) -> Vec<Scan> {
+        self.split_range(range, partitions)
+            .into_iter()
+            .map(|range| self.scan(range))
+            .collect()
+    }
+
+    /// Scans the range like `scan`, but copies up to `batch` entries at a time under a single
+    /// lock acquisition and serves them from a buffer, refilling it when drained.
+    pub fn scan_buffered(&self, range: Range, batch: usize) -> BufferedIter {
+        BufferedIter {
+            skl: self.skiplist.clone(),
+            start: range.start,
+            end: range.end,
+            batch: batch.max(1),
+            done: false,
+            buffer: VecDeque::with_capacity(batch),
+            format: self.format.clone(),
+        }
+    }
+}
+
+/// Size limits on written keys and values. See `Memory::with_limits`.
+#[derive(Clone, Copy, Default)]
+struct Limits {
+    max_key_len: Option<usize>,
+    max_value_len: Option<usize>,
+}
+
+impl Limits {
+    fn check_key(&self, key: &[u8]) -> Result<()> {
+        match self.max_key_len {
+            Some(max) if key.len() > max => Err(anyhow!(
+                "Key of {} bytes exceeds the limit of {} bytes: {:?}",
+                key.len(),
+                max,
+                &key[..key.len().min(32)]
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_value(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        match self.max_value_len {
+            Some(max) if value.len() > max => Err(anyhow!(
+                "Value of {} bytes for key {:?} exceeds the limit of {} bytes",
+                value.len(),
+                &key[..key.len().min(32)],
+                max
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_key(key)?;
+        self.check_value(key, value)
+    }
+}
+
+/// How values are laid out in skiplist nodes. By default a node holds the raw value.
+#[derive(Clone, Default)]
+struct ValueFormat {
+    integrity_checks: bool,
+    blob_threshold: Option<usize>,
+    blobs: Arc<BlobArena>,
+}
+
+impl ValueFormat {
+    /// Encodes a value for storage in a node, appending its checksum and moving it to the
+    /// blob arena as configured.
+    fn encode(&self, key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut stored = value.to_vec();
+        if self.integrity_checks {
+            stored.extend_from_slice(&checksum(key, value).to_be_bytes());
+        }
+        match self.blob_threshold {
+            Some(threshold) if stored.len() > threshold => {
+                let offset = self.blobs.put(&stored);
+                let mut handle = Vec::with_capacity(17);
+                handle.push(VALUE_BLOB);
+                handle.extend_from_slice(&offset.to_be_bytes());
+                handle.extend_from_slice(&(stored.len() as u64).to_be_bytes());
+                handle
+            }
+            Some(_) => {
+                stored.insert(0, VALUE_INLINE);
+                stored
+            }
+            None => stored,
+        }
+    }
+
+    /// Decodes a value stored in a node, resolving blob handles and verifying checksums.
+    fn decode(&self, key: &[u8], mut stored: Vec<u8>) -> Result<Vec<u8>> {
+        if self.blob_threshold.is_some() {
+            stored = match stored.split_first() {
+                Some((&VALUE_INLINE, value)) => value.to_vec(),
+                Some((&VALUE_BLOB, handle)) if handle.len() == 16 => {
+                    let offset = u64::from_be_bytes(handle[..8].try_into().unwrap());
+                    let len = u64::from_be_bytes(handle[8..].try_into().unwrap());
+                    self.blobs.get(offset, len as usize).ok_or_else(|| {
+                        Error::Corrupt(format!("blob handle for key {:?} is dangling", key))
+                    })?
+                }
+                _ => {
+                    let msg = format!("invalid value tag for key {:?}", key);
+                    return Err(Error::Corrupt(msg).into());
+                }
+            };
+        }
+        if self.integrity_checks {
+            verify(key, &mut stored)?;
+        }
+        Ok(stored)
+    }
+
+    /// Returns true if values are stored as is, so reads can skip decoding.
+    fn is_raw(&self) -> bool {
+        !self.integrity_checks && self.blob_threshold.is_none()
+    }
+}
+
+fn checksum(key: &[u8], value: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize()
+}
+
+fn verify(key: &[u8], value: &mut Vec<u8>) -> Result<()> {
+    if value.len() < CHECKSUM_SIZE {
+        return Err(Error::Corrupt("value is missing its checksum".into()).into());
+    }
+    let crc = value.split_off(value.len() - CHECKSUM_SIZE);
+    if u32::from_be_bytes(crc.try_into().unwrap()) != checksum(key, value) {
+        return Err(Error::Corrupt(format!("checksum mismatch for key {:?}", key)).into());
+    }
+    Ok(())
 }
 
 impl Store for Memory {
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let node = self.skiplist.get(key);
-        return if !node.is_null() {
-            unsafe { Ok(Some((*node).get_value().to_owned())) }
-        } else {
-            Ok(None)
-        };
+        self.counters.gets.fetch_add(1, AtomicOrdering::Relaxed);
+        let entry = self.skiplist.get_entry(key);
+        trace_event!(
+            tracing::Level::TRACE,
+            key_len = key.len(),
+            found = entry.is_some(),
+            "get"
+        );
+        match entry {
+            Some((_, value)) => Ok(Some(self.format.decode(key, value)?)),
+            None => {
+                self.counters
+                    .get_misses
+                    .fetch_add(1, AtomicOrdering::Relaxed);
+                Ok(None)
+            }
+        }
+    }
+
+    fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.counters
+            .gets
+            .fetch_add(keys.len() as u64, AtomicOrdering::Relaxed);
+        let values = self.skiplist.get_many(keys);
+        keys.iter()
+            .zip(values)
+            .map(|(key, value)| match value {
+                Some(value) => Ok(Some(self.format.decode(key, value)?)),
+                None => {
+                    self.counters
+                        .get_misses
+                        .fetch_add(1, AtomicOrdering::Relaxed);
+                    Ok(None)
+                }
+            })
+            .collect()
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.skiplist.count())
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.skiplist.count() == 0)
+    }
+
+    fn estimate_count(&self, range: Range) -> Result<u64> {
+        Ok(self.skiplist.estimate_count(&range) as u64)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(!self.skiplist.get(key).is_null())
     }
 
     fn scan(&self, range: Range) -> Scan {
-        Box::new(Iter::new(self.skiplist.clone(), range))
+        self.counters.scans.fetch_add(1, AtomicOrdering::Relaxed);
+        trace_event!(tracing::Level::TRACE, "scan");
+        if self.format.is_raw() {
+            return Box::new(Iter::new(self.skiplist.clone(), range, |node| {
+                Ok(node.get_key_value())
+            }));
+        }
+        let format = self.format.clone();
+        Box::new(Iter::new(self.skiplist.clone(), range, move |node| {
+            let (key, value) = node.get_key_value();
+            let value = format.decode(&key, value)?;
+            Ok((key, value))
+        }))
+    }
+
+    fn scan_keys(&self, range: Range) -> Keys {
+        self.counters.scans.fetch_add(1, AtomicOrdering::Relaxed);
+        Box::new(Iter::new(self.skiplist.clone(), range, |node| {
+            Ok(node.get_key().to_owned())
+        }))
     }
 
     fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
-        self.skiplist.insert(key, value);
+        self.counters.sets.fetch_add(1, AtomicOrdering::Relaxed);
+        trace_event!(
+            tracing::Level::TRACE,
+            key_len = key.len(),
+            value_len = value.len(),
+            "set"
+        );
+        self.limits.check(key, value)?;
+        self.skiplist.insert(key, &self.format.encode(key, value));
+        Ok(())
+    }
+
+    fn set_owned(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.counters.sets.fetch_add(1, AtomicOrdering::Relaxed);
+        self.limits.check(&key, &value)?;
+        let value = if self.format.is_raw() {
+            value
+        } else {
+            self.format.encode(&key, &value)
+        };
+        self.skiplist.insert_owned(key, value);
         Ok(())
     }
 
+    fn set_if_absent(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.limits.check(key, value)?;
+        let inserted = self
+            .skiplist
+            .insert_if_absent(key, &self.format.encode(key, value));
+        if inserted {
+            self.counters.sets.fetch_add(1, AtomicOrdering::Relaxed);
+        }
+        Ok(inserted)
+    }
+
+    fn get_or_insert_with(
+        &mut self,
+        key: &[u8],
+        f: &mut dyn FnMut() -> Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        self.counters.gets.fetch_add(1, AtomicOrdering::Relaxed);
+        self.limits.check_key(key)?;
+        let mut inserted = false;
+        let stored = self.skiplist.try_get_or_insert_with(key, || {
+            let value = f();
+            self.limits.check_value(key, &value)?;
+            inserted = true;
+            Ok(self.format.encode(key, &value))
+        })?;
+        if inserted {
+            self.counters
+                .get_misses
+                .fetch_add(1, AtomicOrdering::Relaxed);
+            self.counters.sets.fetch_add(1, AtomicOrdering::Relaxed);
+        }
+        self.format.decode(key, stored)
+    }
+
     fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.counters.deletes.fetch_add(1, AtomicOrdering::Relaxed);
+        trace_event!(tracing::Level::TRACE, key_len = key.len(), "delete");
         self.skiplist.delete(key);
         Ok(())
     }
 
+    fn range_delete(&mut self, range: Range) -> Result<u64> {
+        Ok(self.skiplist.range_delete(&range) as u64)
+    }
+
     fn flush(&mut self) -> Result<()> {
         Ok(())
     }
+
+    fn flush_is_noop(&self) -> bool {
+        true
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// See `Memory::scan_while`.
+struct ScanWhile<F> {
+    inner: Scan,
+    pred: F,
+    done: bool,
+    /// Where the scan stops is only known from the front, so iterating from the back first
+    /// collects the remaining entries up to the stopping point.
+    tail: Option<Scan>,
+}
+
+impl<F: Fn(&[u8]) -> bool> ScanWhile<F> {
+    fn next_front(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        if self.done {
+            return None;
+        }
+        let item = self.inner.next()?;
+        // Errors are yielded, but end the scan like a failed predicate.
+        let keep = item.as_ref().map_or(true, |(key, _)| (self.pred)(key));
+        self.done = item.is_err() || !keep;
+        keep.then_some(item)
+    }
+}
+
+impl<F: Fn(&[u8]) -> bool> Iterator for ScanWhile<F> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.tail {
+            Some(tail) => tail.next(),
+            None => self.next_front(),
+        }
+    }
+}
+
+impl<F: Fn(&[u8]) -> bool> DoubleEndedIterator for ScanWhile<F> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.tail.is_none() {
+            let tail = std::iter::from_fn(|| self.next_front()).collect::<Vec<_>>();
+            self.tail = Some(Box::new(tail.into_iter()));
+        }
+        self.tail.as_mut()?.next_back()
+    }
 }
 
-struct Iter<C: Comparator, A: Arena> {
+/// A forward scan over a `Memory` store that reads entries in batches. See
+/// `Memory::scan_buffered`.
+pub struct BufferedIter {
+    skl: Skiplist<BytewiseComparator, BlockArena>,
+    /// Where the next batch starts: after the last key read, since the node it was read from
+    /// may be deleted between batches.
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    batch: usize,
+    done: bool,
+    buffer: VecDeque<(Vec<u8>, Vec<u8>)>,
+    format: ValueFormat,
+}
+
+impl Iterator for BufferedIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && !self.done {
+            self.done = !self
+                .skl
+                .read_batch(&self.start, &self.end, self.batch, &mut self.buffer);
+            if let Some((key, _)) = self.buffer.back() {
+                self.start = Bound::Excluded(key.clone());
+            }
+        }
+        let (key, value) = self.buffer.pop_front()?;
+        Some(self.format.decode(&key, value).map(|value| (key, value)))
+    }
+}
+
+/// Reads an item out of a skiplist node.
+type Extract<T> = Box<dyn Fn(&Node) -> Result<T>>;
+
+struct Iter<C: Comparator, A: Arena, T> {
     skl: Skiplist<C, A>,
     range: Range,
     front_cursor: *mut Node,
     back_cursor: *mut Node,
+    /// Set once the cursors meet, either end runs out, or an entry fails to extract. Neither
+    /// end yields anything after that, since the cursors may not be valid to advance.
+    done: bool,
+    extract: Extract<T>,
 }
 
-impl<C: Comparator, A: Arena> Iter<C, A> {
-    fn new(skl: Skiplist<C, A>, range: Range) -> Self {
+impl<C: Comparator, A: Arena, T> Iter<C, A, T> {
+    fn new(
+        skl: Skiplist<C, A>,
+        range: Range,
+        extract: impl Fn(&Node) -> Result<T> + 'static,
+    ) -> Self {
         Self {
             skl,
             range,
             front_cursor: std::ptr::null_mut(),
             back_cursor: std::ptr::null_mut(),
+            done: false,
+            extract: Box::new(extract),
         }
     }
 
-    fn try_next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    /// Extracts an item from a node under the read lock, so that an overwrite can't be seen
+    /// half written.
+    fn read(&self, node: *const Node) -> Result<Option<T>> {
+        unsafe { self.skl.read_node(node, |node| (self.extract)(node)) }.map(Some)
+    }
+
+    /// Checks that a node is within the end bound. The cursors only check the bound at the
+    /// opposite end when stepping, so the first node from either end must be checked here.
+    fn before_end(&self, node: *const Node) -> bool {
+        match &self.range.end {
+            Bound::Included(k) => self.skl.key_is_greater_than_or_equal(k, node),
+            Bound::Excluded(k) => self.skl.key_is_greater_than(k, node),
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// Checks that a node is within the start bound.
+    fn after_start(&self, node: *const Node) -> bool {
+        match &self.range.start {
+            Bound::Included(k) => self.skl.key_is_less_than_or_equal(k, node),
+            Bound::Excluded(k) => self.skl.key_is_less_than(k, node),
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn try_next(&mut self) -> Result<Option<T>> {
         let next = match self.front_cursor.is_null() {
             true => match &self.range.start {
                 Bound::Included(k) => {
                     let node = self.skl.get_greater_or_equal(k);
-                    match self.skl.is_tail(node) {
+                    match self.skl.is_tail(node) || !self.before_end(node) {
                         true => Ok(None),
                         false => {
                             self.front_cursor = node as *mut _;
-                            unsafe { Ok(Some((*node).get_key_value())) }
+                            self.read(node)
                         }
                     }
                 }
                 Bound::Excluded(k) => {
                     let node = self.skl.get_first_greater(k);
-                    match node.is_null() {
+                    match node.is_null() || !self.before_end(node) {
                         true => Ok(None),
                         false => {
                             self.front_cursor = node as *mut _;
-                            unsafe { Ok(Some((*node).get_key_value())) }
+                            self.read(node)
                         }
                     }
                 }
                 Bound::Unbounded => {
                     let node = self.skl.get_first();
-                    match self.skl.is_tail(node) {
+                    match self.skl.is_tail(node) || !self.before_end(node) {
                         true => Ok(None),
                         false => {
                             self.front_cursor = node as *mut _;
-                            unsafe { Ok(Some((*node).get_key_value())) }
+                            self.read(node)
                         }
                     }
                 }
@@ -107,7 +729,7 @@ impl<C: Comparator, A: Arena> Iter<C, A> {
                         Bound::Included(k) => {
                             if self.skl.key_is_greater_than_or_equal(k, next_node) {
                                 self.front_cursor = next_node;
-                                unsafe { Ok(Some((*next_node).get_key_value())) }
+                                self.read(next_node)
                             } else {
                                 Ok(None)
                             }
@@ -115,14 +737,14 @@ impl<C: Comparator, A: Arena> Iter<C, A> {
                         Bound::Excluded(k) => {
                             if self.skl.key_is_greater_than(k, next_node) {
                                 self.front_cursor = next_node;
-                                unsafe { Ok(Some((*next_node).get_key_value())) }
+                                self.read(next_node)
                             } else {
                                 Ok(None)
                             }
                         }
                         Bound::Unbounded => {
                             self.front_cursor = next_node;
-                            unsafe { Ok(Some((*next_node).get_key_value())) }
+                            self.read(next_node)
                         }
                     },
                 }
@@ -131,36 +753,36 @@ impl<C: Comparator, A: Arena> Iter<C, A> {
         next
     }
 
-    fn try_next_back(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    fn try_next_back(&mut self) -> Result<Option<T>> {
         let next = match self.back_cursor.is_null() {
             true => match &self.range.end {
                 Bound::Included(key) => {
                     let node = self.skl.get_less_or_equal(key) as *mut Node;
-                    match self.skl.is_head(node) {
+                    match self.skl.is_head(node) || !self.after_start(node) {
                         true => Ok(None),
                         false => {
                             self.back_cursor = node;
-                            unsafe { Ok(Some((*node).get_key_value())) }
+                            self.read(node)
                         }
                     }
                 }
                 Bound::Excluded(key) => {
                     let node = self.skl.get_first_less(key) as *mut Node;
-                    match node.is_null() {
+                    match node.is_null() || !self.after_start(node) {
                         true => Ok(None),
                         false => {
                             self.back_cursor = node;
-                            unsafe { Ok(Some((*node).get_key_value())) }
+                            self.read(node)
                         }
                     }
                 }
                 Bound::Unbounded => {
                     let node = self.skl.get_last();
-                    match self.skl.is_head(node) {
+                    match self.skl.is_head(node) || !self.after_start(node) {
                         true => Ok(None),
                         false => {
                             self.back_cursor = node as *mut _;
-                            unsafe { Ok(Some((*node).get_key_value())) }
+                            self.read(node)
                         }
                     }
                 }
@@ -173,7 +795,7 @@ impl<C: Comparator, A: Arena> Iter<C, A> {
                         Bound::Included(k) => {
                             if self.skl.key_is_less_than_or_equal(k, prev_node) {
                                 self.back_cursor = prev_node;
-                                unsafe { Ok(Some((*prev_node).get_key_value())) }
+                                self.read(prev_node)
                             } else {
                                 Ok(None)
                             }
@@ -181,14 +803,14 @@ impl<C: Comparator, A: Arena> Iter<C, A> {
                         Bound::Excluded(k) => {
                             if self.skl.key_is_less_than(k, prev_node) {
                                 self.back_cursor = prev_node;
-                                unsafe { Ok(Some((*prev_node).get_key_value())) }
+                                self.read(prev_node)
                             } else {
                                 Ok(None)
                             }
                         }
                         Bound::Unbounded => {
                             self.back_cursor = prev_node;
-                            unsafe { Ok(Some((*prev_node).get_key_value())) }
+                            self.read(prev_node)
                         }
                     },
                 };
@@ -198,15 +820,38 @@ impl<C: Comparator, A: Arena> Iter<C, A> {
     }
 }
 
-impl<C: Comparator, A: Arena> Iterator for Iter<C, A> {
-    type Item = Result<(Vec<u8>, Vec<u8>)>;
+impl<C: Comparator, A: Arena, T> Iterator for Iter<C, A, T> {
+    type Item = Result<T>;
     fn next(&mut self) -> Option<Self::Item> {
-        self.try_next().transpose()
+        if self.done {
+            return None;
+        }
+        let item = self.try_next().transpose();
+        // The node was already yielded from the back.
+        if self.front_cursor == self.back_cursor && !self.front_cursor.is_null() {
+            self.done = true;
+            return None;
+        }
+        self.done = !matches!(item, Some(std::result::Result::Ok(_)));
+        item
     }
 }
-impl<C: Comparator, A: Arena> DoubleEndedIterator for Iter<C, A> {
+
+impl<C: Comparator, A: Arena, T> FusedIterator for Iter<C, A, T> {}
+
+impl<C: Comparator, A: Arena, T> DoubleEndedIterator for Iter<C, A, T> {
     fn next_back(&mut self) -> Option<Self::Item> {
-        self.try_next_back().transpose()
+        if self.done {
+            return None;
+        }
+        let item = self.try_next_back().transpose();
+        // The node was already yielded from the front.
+        if self.front_cursor == self.back_cursor && !self.back_cursor.is_null() {
+            self.done = true;
+            return None;
+        }
+        self.done = !matches!(item, Some(std::result::Result::Ok(_)));
+        item
     }
 }
 
@@ -236,4 +881,721 @@ mod test {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_scan_limit() -> Result<()> {
+        let mut mem = Memory::new();
+        for i in 0..100 {
+            mem.set(&vec![i], &vec![i])?;
+        }
+
+        let keys = mem
+            .scan_limit(Range::from(..), 10, 5)
+            .map(|item| item.map(|(k, _)| k[0]))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, vec![10, 11, 12, 13, 14]);
+
+        let keys = mem
+            .scan_limit(Range::from(..), 10, 5)
+            .rev()
+            .map(|item| item.map(|(k, _)| k[0]))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, vec![14, 13, 12, 11, 10]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_delete() -> Result<()> {
+        let mut mem = Memory::new();
+        for i in 0..30 {
+            mem.set(&vec![i], &vec![i])?;
+        }
+        assert_eq!(mem.range_delete(Range::from(vec![10]..vec![20]))?, 10);
+        assert_eq!(mem.skiplist.count(), 20);
+
+        for i in 0..30 {
+            let expect = if (10..20).contains(&i) {
+                None
+            } else {
+                Some(vec![i])
+            };
+            assert_eq!(mem.get(&vec![i])?, expect);
+        }
+        let expect = (0..10).chain(20..30).collect::<Vec<u8>>();
+        let keys = mem
+            .scan(Range::from(..))
+            .map(|item| item.map(|(k, _)| k[0]))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, expect);
+        let keys = mem
+            .scan(Range::from(..))
+            .rev()
+            .map(|item| item.map(|(k, _)| k[0]))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, expect.into_iter().rev().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_keys() -> Result<()> {
+        let mut mem = Memory::new();
+        for i in 0..20 {
+            mem.set(&vec![i], &vec![i; 64])?;
+        }
+
+        let range = || Range::from(vec![5]..=vec![15]);
+        let keys = mem.scan_keys(range()).collect::<Result<Vec<_>>>()?;
+        let expect = mem
+            .scan(range())
+            .map(|item| item.map(|(k, _)| k))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, expect);
+        assert_eq!(keys.len(), 11);
+
+        let keys = mem.scan_keys(range()).rev().collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, expect.into_iter().rev().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_nearest() -> Result<()> {
+        let mut mem = Memory::new();
+        assert_eq!(mem.find_nearest(&vec![20])?, None);
+        for i in [10, 20, 30] {
+            mem.set(&vec![i], &vec![i + 1])?;
+        }
+        assert_eq!(
+            mem.find_nearest(&vec![20])?,
+            Some((vec![20], vec![21], Ordering::Equal))
+        );
+        assert_eq!(
+            mem.find_nearest(&vec![15])?,
+            Some((vec![20], vec![21], Ordering::Greater))
+        );
+        assert_eq!(
+            mem.find_nearest(&vec![5])?,
+            Some((vec![10], vec![11], Ordering::Greater))
+        );
+        assert_eq!(
+            mem.find_nearest(&vec![35])?,
+            Some((vec![30], vec![31], Ordering::Less))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_contains_key() -> Result<()> {
+        let mut mem = Memory::new();
+        for i in (0..10).step_by(2) {
+            mem.set(&vec![i], &vec![i; 1024])?;
+        }
+        for i in 0..10 {
+            assert_eq!(mem.contains_key(&vec![i])?, i % 2 == 0);
+        }
+        mem.delete(&vec![4])?;
+        assert!(!mem.contains_key(&vec![4])?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_share_and_deep_copy() -> Result<()> {
+        let mut mem = Memory::new();
+        mem.set(b"a", b"1")?;
+
+        let mut shared = mem.share();
+        let mut copy = mem.deep_copy()?;
+        assert_eq!(copy.get(b"a")?, Some(b"1".to_vec()));
+
+        shared.set(b"b", b"2")?;
+        assert_eq!(mem.get(b"b")?, Some(b"2".to_vec()));
+        assert_eq!(copy.get(b"b")?, None);
+
+        copy.set(b"a", b"3")?;
+        copy.delete(b"a")?;
+        copy.set(b"c", b"4")?;
+        assert_eq!(mem.get(b"a")?, Some(b"1".to_vec()));
+        assert_eq!(shared.get(b"c")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_integrity_checks() -> Result<()> {
+        let mut mem = Memory::new().with_integrity_checks(true);
+        for i in 0..10 {
+            mem.set(&vec![i], &vec![i; 8])?;
+        }
+        assert_eq!(mem.get(&vec![3])?, Some(vec![3; 8]));
+        assert_eq!(mem.scan(Range::from(..)).count(), 10);
+
+        let node = mem.skiplist.get(&vec![3]);
+        unsafe {
+            let value = (*node).get_value();
+            *(value.as_ptr() as *mut u8) ^= 0xff;
+        }
+        let err = mem.get(&vec![3]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Corrupt(_))
+        ));
+        let errors = mem
+            .scan(Range::from(..))
+            .filter(|item| item.is_err())
+            .count();
+        assert_eq!(errors, 1);
+        assert_eq!(mem.get(&vec![4])?, Some(vec![4; 8]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_buffered() -> Result<()> {
+        let mut mem = Memory::new();
+        for i in 0..1000u32 {
+            mem.set(&i.to_be_bytes(), &i.to_le_bytes())?;
+        }
+
+        let items = mem
+            .scan_buffered(Range::from(..), 64)
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(items.len(), 1000);
+        for (i, (key, value)) in items.into_iter().enumerate() {
+            assert_eq!(key, (i as u32).to_be_bytes());
+            assert_eq!(value, (i as u32).to_le_bytes());
+        }
+
+        let range = || Range::from(100u32.to_be_bytes().to_vec()..=899u32.to_be_bytes().to_vec());
+        let buffered = mem.scan_buffered(range(), 64).collect::<Result<Vec<_>>>()?;
+        let expect = mem.scan(range()).collect::<Result<Vec<_>>>()?;
+        assert_eq!(buffered, expect);
+        assert_eq!(buffered.len(), 800);
+
+        // Deleting the key the next batch would have started from doesn't derail the scan.
+        let mut scan = mem.scan_buffered(Range::from(..), 2);
+        assert_eq!(
+            scan.next().transpose()?,
+            Some((0u32.to_be_bytes().to_vec(), 0u32.to_le_bytes().to_vec()))
+        );
+        mem.delete(&2u32.to_be_bytes())?;
+        mem.delete(&3u32.to_be_bytes())?;
+        let keys = scan
+            .take(3)
+            .map(|item| item.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, [1u32, 4, 5].map(|i| i.to_be_bytes().to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_blob_threshold() -> Result<()> {
+        let mut mem = Memory::new().with_blob_threshold(1024);
+        let large = (0..1 << 20).map(|i| i as u8).collect::<Vec<u8>>();
+        mem.set(b"large", &large)?;
+        mem.set(b"small", b"value")?;
+
+        // The large value lives in the blob arena, with only a handle in the node.
+        let stored = |key: &[u8]| unsafe { (*mem.skiplist.get(key)).get_value().to_vec() };
+        assert_eq!(stored(b"large").len(), 17);
+        assert_eq!(stored(b"large")[0], VALUE_BLOB);
+        assert_eq!(stored(b"small"), [&[VALUE_INLINE][..], b"value"].concat());
+        assert_eq!(mem.format.blobs.memory_used(), 1 << 20);
+
+        assert_eq!(mem.get(b"large")?, Some(large.clone()));
+        assert_eq!(mem.get(b"small")?, Some(b"value".to_vec()));
+        let items = mem.scan(Range::from(..)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            items,
+            vec![
+                (b"large".to_vec(), large),
+                (b"small".to_vec(), b"value".to_vec())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_get() -> Result<()> {
+        let mut mem = Memory::new();
+        for i in (0..20).step_by(2) {
+            mem.set(&vec![i], &vec![i + 100])?;
+        }
+        let keys: Vec<&[u8]> = vec![&[6], &[7], &[0], &[18], &[19], &[6]];
+        assert_eq!(
+            mem.multi_get(&keys)?,
+            vec![
+                Some(vec![106]),
+                None,
+                Some(vec![100]),
+                Some(vec![118]),
+                None,
+                Some(vec![106])
+            ]
+        );
+        assert_eq!(mem.multi_get(&[])?, Vec::<Option<Vec<u8>>>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics() -> Result<()> {
+        let mut mem = Memory::new();
+        mem.set(b"a", b"1")?;
+        mem.set(b"b", b"2")?;
+        mem.set(b"a", b"3")?;
+        mem.get(b"a")?;
+        mem.get(b"c")?;
+        mem.multi_get(&[b"b", b"d"])?;
+        mem.delete(b"b")?;
+        mem.scan(Range::from(..)).count();
+        mem.scan_keys(Range::from(..)).count();
+
+        let metrics = StoreMetrics {
+            gets: 4,
+            get_misses: 2,
+            sets: 3,
+            deletes: 1,
+            scans: 2,
+        };
+        assert_eq!(mem.metrics(), metrics);
+        assert_eq!(mem.share().metrics(), metrics);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_while() -> Result<()> {
+        let mut mem = Memory::new();
+        for key in ["a1", "a2", "b1", "a3", "c1"] {
+            mem.set(key.as_bytes(), b"v")?;
+        }
+        let keys = |scan: Scan| {
+            scan.map(|item| item.map(|(k, _)| String::from_utf8(k).unwrap()))
+                .collect::<Result<Vec<_>>>()
+        };
+
+        // Keys are scanned in order, so a3 comes before b1.
+        let scan = mem.scan_while(Range::from(..), |key| key.starts_with(b"a"));
+        assert_eq!(keys(scan)?, vec!["a1", "a2", "a3"]);
+
+        // The scan stops at b1 rather than skipping it, so c1 is never returned.
+        let scan = mem.scan_while(Range::from(..), |key| !key.starts_with(b"b"));
+        assert_eq!(keys(scan)?, vec!["a1", "a2", "a3"]);
+        let scan = mem.scan_while(Range::from(..), |key| !key.starts_with(b"b"));
+        assert_eq!(keys(Box::new(scan.rev()))?, vec!["a3", "a2", "a1"]);
+
+        let mut scan = mem.scan_while(Range::from(..), |key| !key.starts_with(b"b"));
+        assert_eq!(
+            scan.next().transpose()?.map(|(k, _)| k),
+            Some(b"a1".to_vec())
+        );
+        assert_eq!(
+            scan.next_back().transpose()?.map(|(k, _)| k),
+            Some(b"a3".to_vec())
+        );
+        assert_eq!(
+            scan.next().transpose()?.map(|(k, _)| k),
+            Some(b"a2".to_vec())
+        );
+        assert!(scan.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let mut mem = Memory::new();
+        mem.verify()?;
+        for i in [5u8, 1, 9, 3, 7, 2, 8] {
+            mem.set(&[i], &[i])?;
+        }
+        mem.verify()?;
+
+        // Swap two adjacent keys, breaking their order.
+        let (a, b) = (mem.skiplist.get(&[2]), mem.skiplist.get(&[3]));
+        unsafe {
+            std::ptr::swap(
+                (*a).get_key().as_ptr() as *mut u8,
+                (*b).get_key().as_ptr() as *mut u8,
+            );
+        }
+        let err = mem.verify().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Corrupt(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_len() -> Result<()> {
+        let mut mem = Memory::new();
+        assert!(mem.is_empty()?);
+        for i in 0..10 {
+            mem.set(&[i], &[i])?;
+        }
+        for i in [2, 5, 8] {
+            mem.delete(&[i])?;
+        }
+        mem.set(&[0], &[1])?;
+        assert_eq!(mem.len()?, 7);
+        assert!(!mem.is_empty()?);
+        assert_eq!(mem.len()?, mem.scan(Range::from(..)).count());
+        mem.verify()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_missing() -> Result<()> {
+        let mut mem = Memory::new();
+        mem.delete(b"a")?;
+        mem.set(b"b", b"1")?;
+        mem.delete(b"a")?;
+        mem.delete(b"c")?;
+        assert_eq!(mem.get(b"a")?, None);
+        assert_eq!(mem.get(b"b")?, Some(b"1".to_vec()));
+        assert_eq!(mem.len()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_if_absent() -> Result<()> {
+        let mut mem = Memory::new();
+        mem.set(b"b", b"1")?;
+        assert!(mem.set_if_absent(b"a", b"2")?);
+        assert!(mem.set_if_absent(b"c", b"3")?);
+        assert!(!mem.set_if_absent(b"b", b"4")?);
+        assert!(!mem.set_if_absent(b"a", b"5")?);
+
+        assert_eq!(mem.get(b"a")?, Some(b"2".to_vec()));
+        assert_eq!(mem.get(b"b")?, Some(b"1".to_vec()));
+        assert_eq!(mem.get(b"c")?, Some(b"3".to_vec()));
+        assert_eq!(mem.len()?, 3);
+        mem.verify()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_estimate_count() -> Result<()> {
+        let mut mem = Memory::new();
+        for i in 0..100u32 {
+            mem.set(&i.to_be_bytes(), &[])?;
+        }
+        // Small stores are counted exactly.
+        assert_eq!(mem.estimate_count(Range::from(..))?, 100);
+        for i in 100..10000u32 {
+            mem.set(&i.to_be_bytes(), &[])?;
+        }
+
+        let range = || Range::from(2500u32.to_be_bytes().to_vec()..7500u32.to_be_bytes().to_vec());
+        let estimate = mem.estimate_count(range())?;
+        assert!(
+            (3750..=6250).contains(&estimate),
+            "estimate {} for 5000 keys",
+            estimate
+        );
+        let estimate = mem.estimate_count(Range::from(..))?;
+        assert!(
+            (7500..=12500).contains(&estimate),
+            "estimate {} for 10000 keys",
+            estimate
+        );
+        assert_eq!(mem.estimate_count(Range::from(vec![0xff]..))?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_key() -> Result<()> {
+        let mut mem = Memory::new();
+        mem.set(b"", b"empty")?;
+        assert_eq!(mem.get(b"")?, Some(b"empty".to_vec()));
+        mem.set(b"a", b"1")?;
+        mem.set(b"\0", b"2")?;
+        mem.set(b"", b"again")?;
+        mem.verify()?;
+
+        assert_eq!(mem.get(b"")?, Some(b"again".to_vec()));
+        assert_eq!(mem.len()?, 3);
+        let keys = mem.scan_keys(Range::from(..)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, vec![b"".to_vec(), b"\0".to_vec(), b"a".to_vec()]);
+        let keys = mem
+            .scan_keys(Range::from(..))
+            .rev()
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, vec![b"a".to_vec(), b"\0".to_vec(), b"".to_vec()]);
+        assert_eq!(
+            mem.find_nearest(b"")?,
+            Some((vec![], b"again".to_vec(), Ordering::Equal))
+        );
+
+        mem.delete(b"")?;
+        assert_eq!(mem.get(b"")?, None);
+        assert_eq!(mem.len()?, 2);
+        mem.verify()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_for_each() -> Result<()> {
+        for mem in [Memory::new(), Memory::new().with_integrity_checks(true)] {
+            let mut mem = mem;
+            for i in 0..50u8 {
+                mem.set(&[i], &vec![i; i as usize])?;
+            }
+            let range = || Range::from(vec![5]..=vec![25]);
+            let mut sum = 0u64;
+            mem.for_each(range(), |_, value| {
+                sum += value.iter().map(|&b| b as u64).sum::<u64>();
+                Ok(())
+            })?;
+            let expect = mem
+                .scan(range())
+                .map(|item| item.map(|(_, v)| v.iter().map(|&b| b as u64).sum::<u64>()))
+                .sum::<Result<u64>>()?;
+            assert_eq!(sum, expect);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_owned() -> Result<()> {
+        for integrity_checks in [false, true] {
+            let mut owned = Memory::new().with_integrity_checks(integrity_checks);
+            let mut borrowed = Memory::new().with_integrity_checks(integrity_checks);
+            for i in [3u8, 1, 2, 1, 0, 3] {
+                owned.set_owned(vec![i], vec![i; i as usize + 1])?;
+                borrowed.set(&[i], &vec![i; i as usize + 1])?;
+            }
+            owned.set_owned(vec![2], b"overwritten".to_vec())?;
+            borrowed.set(&[2], b"overwritten")?;
+            assert_eq!(
+                owned.scan(Range::from(..)).collect::<Result<Vec<_>>>()?,
+                borrowed.scan(Range::from(..)).collect::<Result<Vec<_>>>()?
+            );
+            assert_eq!(owned.len()?, 4);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_both_ends() -> Result<()> {
+        let mut mem = Memory::new();
+        for i in 0..5u8 {
+            mem.set(&[i], &[i])?;
+        }
+        let mut scan = mem.scan(Range::from(..));
+        assert_eq!(scan.next().transpose()?, Some((vec![0], vec![0])));
+        assert_eq!(scan.next_back().transpose()?, Some((vec![4], vec![4])));
+        assert_eq!(scan.next().transpose()?, Some((vec![1], vec![1])));
+        assert_eq!(scan.next_back().transpose()?, Some((vec![3], vec![3])));
+        assert_eq!(scan.next().transpose()?, Some((vec![2], vec![2])));
+        assert_eq!(scan.next_back().transpose()?, None);
+        assert_eq!(scan.next().transpose()?, None);
+
+        // Cursors that meet on a seek from one end.
+        let mut scan = mem.scan(Range::from(vec![1]..=vec![2]));
+        assert_eq!(scan.next_back().transpose()?, Some((vec![2], vec![2])));
+        assert_eq!(scan.next_back().transpose()?, Some((vec![1], vec![1])));
+        assert_eq!(scan.next().transpose()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_or_insert_with() -> Result<()> {
+        for mut mem in [Memory::new(), Memory::new().with_integrity_checks(true)] {
+            let mut calls = 0;
+            let mut f = || {
+                calls += 1;
+                b"computed".to_vec()
+            };
+            assert_eq!(mem.get_or_insert_with(b"key", &mut f)?, b"computed");
+            assert_eq!(mem.get_or_insert_with(b"key", &mut f)?, b"computed");
+            assert_eq!(calls, 1);
+            assert_eq!(mem.get(b"key")?, Some(b"computed".to_vec()));
+
+            mem.set(b"other", b"existing")?;
+            assert_eq!(
+                mem.get_or_insert_with(b"other", &mut || unreachable!())?,
+                b"existing"
+            );
+            assert_eq!(mem.len()?, 2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_fused_after_error() -> Result<()> {
+        let mut mem = Memory::new().with_integrity_checks(true);
+        for i in 0..10u8 {
+            mem.set(&[i], &[i; 8])?;
+        }
+        let node = mem.skiplist.get(&[3]);
+        unsafe { *((*node).get_value().as_ptr() as *mut u8) ^= 0xff };
+
+        let mut scan = mem.scan(Range::from(..));
+        for i in 0..3u8 {
+            assert_eq!(scan.next().transpose()?, Some((vec![i], vec![i; 8])));
+        }
+        assert!(scan.next().unwrap().is_err());
+        assert!(scan.next().is_none());
+        assert!(scan.next().is_none());
+        assert!(scan.next_back().is_none());
+
+        let mut scan = mem.scan(Range::from(vec![2]..));
+        assert_eq!(scan.next_back().transpose()?, Some((vec![9], vec![9; 8])));
+        assert!(scan.by_ref().rev().any(|item| item.is_err()));
+        assert!(scan.next().is_none());
+
+        // An exhausted scan stays exhausted.
+        let mut keys = mem.scan_keys(Range::from(vec![8]..));
+        assert_eq!(keys.by_ref().count(), 2);
+        assert!(keys.next().is_none());
+        assert!(keys.next_back().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_rev() -> Result<()> {
+        let mut mem = Memory::new();
+        for i in 0..10u8 {
+            mem.set(&[i], &[i])?;
+        }
+        let keys = |scan: Scan| -> Result<Vec<u8>> {
+            scan.map(|item| item.map(|(key, _)| key[0])).collect()
+        };
+        assert_eq!(
+            keys(mem.scan_rev(Range::from(..)))?,
+            (0..10).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            keys(mem.scan_rev(Range::from(vec![3]..vec![7])))?,
+            vec![6, 5, 4, 3]
+        );
+        assert_eq!(
+            keys(mem.scan_rev(Range::from(vec![3]..=vec![7])))?,
+            vec![7, 6, 5, 4, 3]
+        );
+
+        // The first node from either end must still respect the opposite bound.
+        assert_eq!(
+            keys(mem.scan_rev(Range::from(vec![20]..=vec![30])))?,
+            vec![]
+        );
+        assert_eq!(keys(mem.scan(Range::from(..vec![0])))?, vec![]);
+        assert_eq!(keys(mem.scan(Range::from(vec![5]..vec![5])))?, vec![]);
+
+        // Iterating the reversed scan from the back yields ascending order again.
+        assert_eq!(
+            keys(Box::new(mem.scan_rev(Range::from(..vec![3])).rev()))?,
+            vec![0, 1, 2]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_usage() -> Result<()> {
+        let mut mem = Memory::new();
+        let empty = mem.memory_usage();
+        assert_eq!(empty.data_bytes, 0);
+        assert_eq!(empty.entry_count, 0);
+        assert!(empty.node_overhead_bytes > 0);
+
+        for i in 0..100u64 {
+            mem.set(&i.to_be_bytes(), &[0; 16])?;
+        }
+        let usage = mem.memory_usage();
+        assert_eq!(usage.data_bytes, 100 * 24);
+        assert_eq!(usage.entry_count, 100);
+        assert!(usage.node_overhead_bytes > empty.node_overhead_bytes);
+        assert!(usage.arena_bytes >= usage.node_overhead_bytes);
+        assert!(usage.total_bytes() >= usage.data_bytes + usage.node_overhead_bytes);
+
+        // Replacing values is accounted for, in place or not.
+        mem.set(&0u64.to_be_bytes(), &[1; 16])?;
+        mem.set(&1u64.to_be_bytes(), &[1; 32])?;
+        mem.set_owned(2u64.to_be_bytes().to_vec(), vec![])?;
+        assert_eq!(mem.memory_usage().data_bytes, 100 * 24 + 16 - 16);
+
+        mem.delete(&0u64.to_be_bytes())?;
+        mem.range_delete(Range::from(90u64.to_be_bytes().to_vec()..))?;
+        let after = mem.memory_usage();
+        assert_eq!(after.data_bytes, 89 * 24);
+        assert_eq!(after.entry_count, 89);
+        assert!(after.node_overhead_bytes < usage.node_overhead_bytes);
+        assert!(after.arena_bytes >= usage.arena_bytes);
+
+        // Stored bytes include checksums.
+        let mut mem = Memory::new().with_integrity_checks(true);
+        mem.set(b"key", b"value")?;
+        assert_eq!(mem.memory_usage().data_bytes, 3 + 5 + CHECKSUM_SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits() -> Result<()> {
+        let mut mem = Memory::new().with_limits(4, 8);
+        mem.set(b"abcd", &[0; 8])?;
+        assert!(mem.set(b"abcde", b"v").is_err());
+        assert!(mem.set(b"k", &[0; 9]).is_err());
+        assert!(mem.set_owned(b"abcde".to_vec(), b"v".to_vec()).is_err());
+        assert!(mem.set_if_absent(b"k", &[0; 9]).is_err());
+        assert!(mem
+            .get_or_insert_with(b"abcde", &mut || b"v".to_vec())
+            .is_err());
+        assert!(mem.get_or_insert_with(b"k", &mut || vec![0; 9]).is_err());
+        assert_eq!(
+            mem.get_or_insert_with(b"k", &mut || vec![0; 8])?,
+            vec![0; 8]
+        );
+
+        // Rejected writes store nothing, and limits carry over to shared handles and copies.
+        assert_eq!(mem.collect_range(Range::from(..))?.len(), 2);
+        assert!(mem.share().set(b"abcde", b"v").is_err());
+        assert!(mem.deep_copy()?.set(b"abcde", b"v").is_err());
+
+        // The default is no limits.
+        Memory::new().set(&[0; 1024], &[0; 1 << 20])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_par_scan() -> Result<()> {
+        let mut mem = Memory::new();
+        for i in 0..10_000u32 {
+            mem.set(&i.to_be_bytes(), &i.to_le_bytes())?;
+        }
+        let ranges = [
+            Range::from(..),
+            Range::from(100u32.to_be_bytes().to_vec()..=9_000u32.to_be_bytes().to_vec()),
+            Range::from(..5u32.to_be_bytes().to_vec()),
+        ];
+        for range in ranges {
+            let expect = mem.collect_range(range.clone())?;
+            for partitions in [0, 1, 2, 7, 100] {
+                let scans = mem.par_scan(range.clone(), partitions);
+                assert_eq!(scans.len(), partitions.max(1));
+                // Concatenated in order, the partitions give the full scan exactly once.
+                let parts = scans
+                    .into_iter()
+                    .map(|scan| scan.collect::<Result<Vec<_>>>())
+                    .collect::<Result<Vec<_>>>()?;
+                assert_eq!(parts.concat(), expect);
+                if expect.len() >= 1000 && partitions > 1 {
+                    assert!(parts.iter().all(|part| !part.is_empty()));
+                }
+            }
+        }
+
+        // The sub-ranges can be scanned on separate threads.
+        let total = std::thread::scope(|s| {
+            let handles: Vec<_> = mem
+                .split_range(Range::from(..), 4)
+                .into_iter()
+                .map(|range| {
+                    let mem = mem.share();
+                    s.spawn(move || mem.scan(range).count())
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .sum::<usize>()
+        });
+        assert_eq!(total, 10_000);
+        Ok(())
+    }
 }
@@ -1,15 +1,40 @@
 extern crate core;
 
+/// Emits a tracing event with the `tracing` feature, and expands to nothing without it, so
+/// that the fields aren't even evaluated. Events record sizes and IDs, never keys or values.
+macro_rules! trace_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::event!($($arg)*);
+    };
+}
+
+mod append_only;
 mod arena;
+mod async_store;
 mod comparator;
+mod encrypted;
+pub mod key;
+mod lock;
 mod memory;
+mod prefixed;
 mod skiplist;
 mod mvcc;
 
+pub use arena::{Arena, BlockArena};
+pub use comparator::{BytewiseComparator, Comparator};
+pub use mvcc::RecordKey;
+pub use skiplist::Skiplist;
+
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
 use std::{
+    any::Any,
+    cmp::Ordering,
     fmt::Display,
+    io::{Read, Write},
+    iter::Peekable,
     ops::{Bound, RangeBounds},
 };
 
@@ -21,19 +46,269 @@ pub trait Store: Send + Sync {
     /// Gets a value for a key, if it exists.
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
 
+    /// Gets a value for a key as `Bytes`, for callers like the RPC layer that pass values on
+    /// as `Bytes`. Stores that keep `Bytes` can override this to return them without copying.
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        Ok(self.get(key)?.map(Bytes::from))
+    }
+
+    /// Gets the values for several keys, returned in the same order as the keys, with None for
+    /// keys that don't exist.
+    fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Checks whether a key exists.
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.get(key).map(|value| value.is_some())
+    }
+
     /// Iterates over an ordered range of key/value pairs.
     fn scan(&self, range: Range) -> Scan;
 
+    /// Returns the number of keys in the store.
+    fn len(&self) -> Result<usize> {
+        self.scan_keys(Range::from(..))
+            .try_fold(0, |count, key| key.map(|_| count + 1))
+    }
+
+    /// Returns true if the store has no keys.
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self
+            .scan_keys(Range::from(..))
+            .next()
+            .transpose()?
+            .is_none())
+    }
+
+    /// Estimates the number of keys in a range. The result is approximate, and only meant for
+    /// decisions like query planning where an exact count isn't worth a full scan.
+    fn estimate_count(&self, range: Range) -> Result<u64> {
+        self.scan_keys(range)
+            .try_fold(0, |count, key| key.map(|_| count + 1))
+    }
+
+    /// Iterates over an ordered range of key/value pairs in descending order, largest key
+    /// first.
+    fn scan_rev(&self, range: Range) -> Scan {
+        Box::new(self.scan(range).rev())
+    }
+
+    /// Iterates over all key/value pairs in order.
+    fn iter_all(&self) -> Scan {
+        self.scan(Range::from(..))
+    }
+
+    /// Iterates over the keys of an ordered range, without copying values.
+    fn scan_keys(&self, range: Range) -> Keys {
+        Box::new(self.scan(range).map(|item| item.map(|(key, _)| key)))
+    }
+
     /// Sets a value for a key, replacing the existing value if any.
     fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()>;
 
+    /// Sets a value for a key, taking ownership of both. Stores that keep owned buffers can
+    /// override this to store them without copying.
+    fn set_owned(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set(&key, &value)
+    }
+
+    /// Sets a value for a key from `Bytes`. Stores that keep `Bytes` can override this to store
+    /// them without copying.
+    fn set_bytes(&mut self, key: Bytes, value: Bytes) -> Result<()> {
+        self.set(&key, &value)
+    }
+
+    /// Sets a value for a key only if the key does not exist, returning true if it was set.
+    fn set_if_absent(&mut self, key: &[u8], value: &[u8]) -> Result<bool> {
+        if self.contains_key(key)? {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Gets the value for a key, first setting it to the result of `f` if the key does not
+    /// exist. `f` is called at most once. Stores that can should check and set atomically.
+    fn get_or_insert_with(
+        &mut self,
+        key: &[u8],
+        f: &mut dyn FnMut() -> Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        if let Some(value) = self.get(key)? {
+            return Ok(value);
+        }
+        let value = f();
+        self.set(key, &value)?;
+        Ok(value)
+    }
+
     /// Deletes a key, or does nothing if it does not exist.
     fn delete(&mut self, key: &[u8]) -> Result<()>;
 
+    /// Deletes all keys in a range, returning the number of keys removed.
+    fn range_delete(&mut self, range: Range) -> Result<u64> {
+        let keys = self
+            .scan(range)
+            .map(|item| item.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?;
+        for key in &keys {
+            self.delete(key)?;
+        }
+        Ok(keys.len() as u64)
+    }
+
     /// Flushes any buffered data to the underlying storage medium.
     fn flush(&mut self) -> Result<()>;
+
+    /// Returns true if `flush` does nothing, as for in-memory stores, so that async callers can
+    /// skip it rather than hand it to a blocking thread.
+    fn flush_is_noop(&self) -> bool {
+        false
+    }
+
+    /// Returns the store as `Any`, so that callers holding a `dyn Store` can downcast it to its
+    /// concrete type with `downcast_ref`. Stores that support this return `self`; by default
+    /// the store can't be downcast to anything.
+    fn as_any(&self) -> &dyn Any {
+        &NotDowncastable
+    }
+
+    /// Flushes and closes the store, consuming it. Unlike `flush`, this is called exactly once,
+    /// so stores that buffer writes can use it to make them durable before being dropped.
+    fn close(mut self: Box<Self>) -> Result<()> {
+        self.flush()
+    }
+
+    /// Writes all key/value pairs to a writer, each as a big-endian u32 length-prefixed key
+    /// followed by a length-prefixed value, and returns the number of pairs written.
+    fn dump(&self, w: &mut dyn Write) -> Result<u64> {
+        let mut count = 0;
+        for item in self.iter_all() {
+            let (key, value) = item?;
+            for chunk in [&key, &value] {
+                w.write_all(&(chunk.len() as u32).to_be_bytes())?;
+                w.write_all(chunk)?;
+            }
+            count += 1;
+        }
+        w.flush()?;
+        Ok(count)
+    }
+
+    /// Reads key/value pairs written by `dump` until the end of the reader and sets them,
+    /// returning the number of pairs loaded.
+    fn load(&mut self, r: &mut dyn Read) -> Result<u64> {
+        let mut count = 0;
+        while let Some(key) = read_chunk(r)? {
+            let value = read_chunk(r)?.ok_or_else(|| anyhow!("Unexpected end of dump"))?;
+            self.set(&key, &value)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Iterates over a window of an ordered range, skipping the first `offset` entries and
+    /// yielding at most `limit`. The window is always taken from the front of the range, so
+    /// iterating it from the back yields the same window in reverse. Errors are never skipped.
+    fn scan_limit(&self, range: Range, offset: usize, limit: usize) -> Scan {
+        Box::new(ScanLimit::new(self.scan(range), offset, limit))
+    }
+
+    /// Applies write operations in order, e.g. a diff from `diff`.
+    fn apply_diff(&mut self, ops: Vec<WriteOp>) -> Result<()> {
+        for op in ops {
+            match op {
+                WriteOp::Set(key, value) => self.set(&key, &value)?,
+                WriteOp::Delete(key) => self.delete(&key)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects an ordered range of key/value pairs into a Vec. Only meant for ranges known to
+    /// be small; see `collect_range_limited` for a guarded version.
+    fn collect_range(&self, range: Range) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.scan(range).collect()
+    }
+
+    /// Like `collect_range`, but errors instead of collecting more than `max` pairs.
+    fn collect_range_limited(&self, range: Range, max: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        for item in self.scan(range) {
+            if pairs.len() == max {
+                return Err(anyhow!("Range has more than {} entries", max));
+            }
+            pairs.push(item?);
+        }
+        Ok(pairs)
+    }
+}
+
+/// A write to a store.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WriteOp {
+    Set(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+/// Computes the writes that turn the contents of `a` into those of `b`, in key order, by
+/// merging scans of both. Only keys that were added, changed or removed get an op. Both stores
+/// must scan in bytewise key order, as the built-in ones do; see `diff_with` otherwise.
+pub fn diff(a: &dyn Store, b: &dyn Store) -> Result<Vec<WriteOp>> {
+    diff_with(a, b, &BytewiseComparator::default())
+}
+
+/// Like `diff`, for stores that both scan in the order of `cmp`.
+pub fn diff_with<C: Comparator>(a: &dyn Store, b: &dyn Store, cmp: &C) -> Result<Vec<WriteOp>> {
+    let mut a = PeekableScan::new(a.scan(Range::from(..)));
+    let mut b = PeekableScan::new(b.scan(Range::from(..)));
+    let mut ops = Vec::new();
+    loop {
+        let order = match (a.peek(), b.peek()) {
+            (None, None) => break,
+            (Some(Ok(_)), None) => Ordering::Less,
+            (None, Some(Ok(_))) => Ordering::Greater,
+            (Some(Ok((a_key, _))), Some(Ok((b_key, _)))) => cmp.compare(a_key, b_key),
+            // Take the error from whichever scan has it.
+            (Some(Err(_)), _) => Ordering::Less,
+            (_, Some(Err(_))) => Ordering::Greater,
+        };
+        match order {
+            Ordering::Less => {
+                let (key, _) = a.next().unwrap()?;
+                ops.push(WriteOp::Delete(key));
+            }
+            Ordering::Greater => {
+                let (key, value) = b.next().unwrap()?;
+                ops.push(WriteOp::Set(key, value));
+            }
+            Ordering::Equal => {
+                let (_, a_value) = a.next().unwrap()?;
+                let (key, b_value) = b.next().unwrap()?;
+                if a_value != b_value {
+                    ops.push(WriteOp::Set(key, b_value));
+                }
+            }
+        }
+    }
+    Ok(ops)
+}
+
+/// Reads a length-prefixed chunk written by `Store::dump`. Returns None if the reader is at
+/// its end, and errors if the chunk is truncated.
+fn read_chunk(r: &mut dyn Read) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    if r.read(&mut len[..1])? == 0 {
+        return Ok(None);
+    }
+    r.read_exact(&mut len[1..])?;
+    let mut chunk = vec![0; u32::from_be_bytes(len) as usize];
+    r.read_exact(&mut chunk)?;
+    Ok(Some(chunk))
 }
 
+#[derive(Clone, Debug)]
 pub struct Range {
     start: Bound<Vec<u8>>,
     end: Bound<Vec<u8>>,
@@ -55,14 +330,21 @@ impl Range {
         }
     }
 
-    fn contains(&self, v: &[u8]) -> bool {
+    /// Checks whether the range contains a key in bytewise order.
+    pub fn contains(&self, v: &[u8]) -> bool {
+        self.contains_with(v, &BytewiseComparator::default())
+    }
+
+    /// Checks whether the range contains a key in the order of the given comparator, for
+    /// ranges over keys that aren't ordered bytewise.
+    pub fn contains_with<C: Comparator>(&self, v: &[u8], cmp: &C) -> bool {
         (match &self.start {
-            Bound::Included(start) => &**start <= v,
-            Bound::Excluded(start) => &**start < v,
+            Bound::Included(start) => cmp.compare(start, v) != Ordering::Greater,
+            Bound::Excluded(start) => cmp.compare(start, v) == Ordering::Less,
             Bound::Unbounded => true,
         }) && (match &self.end {
-            Bound::Included(end) => v <= &**end,
-            Bound::Excluded(end) => v < &**end,
+            Bound::Included(end) => cmp.compare(v, end) != Ordering::Greater,
+            Bound::Excluded(end) => cmp.compare(v, end) == Ordering::Less,
             Bound::Unbounded => true,
         })
     }
@@ -86,13 +368,429 @@ impl RangeBounds<Vec<u8>> for Range {
     }
 }
 
+/// Returned by the default `Store::as_any`.
+struct NotDowncastable;
+
 pub type Scan = Box<dyn DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
 
+pub type Keys = Box<dyn DoubleEndedIterator<Item = Result<Vec<u8>>>>;
+
+/// A scan adapter that hides entries whose value is a tombstone, as decided by `is_tombstone`.
+/// Errors from the inner scan are passed through untouched.
+pub struct TombstoneFilter {
+    inner: Scan,
+    is_tombstone: fn(&[u8]) -> bool,
+}
+
+impl TombstoneFilter {
+    pub fn new(inner: Scan, is_tombstone: fn(&[u8]) -> bool) -> Self {
+        Self {
+            inner,
+            is_tombstone,
+        }
+    }
+
+    fn keep(&self, item: &Result<(Vec<u8>, Vec<u8>)>) -> bool {
+        match item {
+            Ok((_, value)) => !(self.is_tombstone)(value),
+            Err(_) => true,
+        }
+    }
+}
+
+impl Iterator for TombstoneFilter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(item) = self.inner.next() {
+            if self.keep(&item) {
+                return Some(item);
+            }
+        }
+        None
+    }
+}
+
+impl DoubleEndedIterator for TombstoneFilter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while let Some(item) = self.inner.next_back() {
+            if self.keep(&item) {
+                return Some(item);
+            }
+        }
+        None
+    }
+}
+
+/// A window of a scan, as returned by `Store::scan_limit`. Entries are read from the inner
+/// scan as they're yielded, and the first error ends the window. Where the window ends is only
+/// known by counting from the front, so iterating from the back reads the rest of the window
+/// into a buffer first.
+struct ScanLimit {
+    inner: Scan,
+    /// Entries still to skip before the window starts.
+    offset: usize,
+    /// Entries left to yield from the front of the inner scan.
+    limit: usize,
+    /// The rest of the window, once iterating from the back has read it.
+    buffered: Option<Scan>,
+}
+
+impl ScanLimit {
+    fn new(inner: Scan, offset: usize, limit: usize) -> Self {
+        Self {
+            inner,
+            offset,
+            limit,
+            buffered: None,
+        }
+    }
+
+    fn next_unbuffered(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        while self.limit > 0 {
+            match self.inner.next() {
+                Some(Ok(_)) if self.offset > 0 => self.offset -= 1,
+                Some(Ok(pair)) => {
+                    self.limit -= 1;
+                    return Some(Ok(pair));
+                }
+                Some(Err(err)) => {
+                    self.limit = 0;
+                    return Some(Err(err));
+                }
+                None => self.limit = 0,
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for ScanLimit {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.buffered {
+            Some(buffered) => buffered.next(),
+            None => self.next_unbuffered(),
+        }
+    }
+}
+
+impl DoubleEndedIterator for ScanLimit {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.buffered.is_none() {
+            let window = std::iter::from_fn(|| self.next_unbuffered()).collect::<Vec<_>>();
+            self.buffered = Some(Box::new(window.into_iter()));
+        }
+        self.buffered.as_mut()?.next_back()
+    }
+}
+
+/// A scan that can look at its next entry from the front without consuming it, e.g. to
+/// compare the heads of several scans when merging them.
+pub struct PeekableScan {
+    inner: Peekable<Scan>,
+}
+
+impl PeekableScan {
+    pub fn new(inner: Scan) -> Self {
+        Self {
+            inner: inner.peekable(),
+        }
+    }
+
+    /// Returns the entry the next call to `next` will yield, without consuming it.
+    pub fn peek(&mut self) -> Option<&Result<(Vec<u8>, Vec<u8>)>> {
+        self.inner.peek()
+    }
+}
+
+impl Iterator for PeekableScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+impl DoubleEndedIterator for PeekableScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::memory::Memory;
+    use super::prefixed::PrefixedStore;
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Orders keys in reverse bytewise order.
+    #[derive(Clone, Default)]
+    struct ReverseComparator;
+
+    impl Comparator for ReverseComparator {
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            b.cmp(a)
+        }
+
+        fn name(&self) -> &str {
+            "ReverseComparator"
+        }
+
+        fn successor(&self, key: &[u8]) -> Vec<u8> {
+            key.to_vec()
+        }
+    }
+
+    #[test]
+    fn test_range_contains_with() {
+        let cmp = ReverseComparator;
+        // In reverse order, c comes before b, which comes before a.
+        let range = Range::from(b"c".to_vec()..=b"a".to_vec());
+        assert!(!range.contains(b"b"));
+        assert!(range.contains_with(b"b", &cmp));
+        assert!(range.contains_with(b"a", &cmp));
+        assert!(!range.contains_with(b"d", &cmp));
+        let range = Range::from(b"c".to_vec()..b"a".to_vec());
+        assert!(!range.contains_with(b"a", &cmp));
+        assert!(range.contains_with(b"b", &BytewiseComparator::default()) == range.contains(b"b"));
+
+        // Skiplists check ranges in their own order.
+        let skiplist = Skiplist::new(cmp, BlockArena::default());
+        for key in [b"a", b"b", b"c", b"d"] {
+            skiplist.insert(key, b"");
+        }
+        assert_eq!(
+            skiplist.estimate_count(&Range::from(b"c".to_vec()..=b"a".to_vec())),
+            3
+        );
+        assert_eq!(
+            skiplist
+                .split_keys(&Range::from(b"d".to_vec()..b"a".to_vec()), 3)
+                .len(),
+            2
+        );
+    }
+
+    /// A store that buffers writes until flushed, counting flushes.
+    struct BufferedStore {
+        inner: Memory,
+        pending: Vec<(Vec<u8>, Vec<u8>)>,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    impl Store for BufferedStore {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            self.inner.get(key)
+        }
+
+        fn scan(&self, range: Range) -> Scan {
+            self.inner.scan(range)
+        }
+
+        fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+            self.pending.push((key.to_vec(), value.to_vec()));
+            Ok(())
+        }
+
+        fn delete(&mut self, key: &[u8]) -> Result<()> {
+            self.inner.delete(key)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            for (key, value) in self.pending.drain(..) {
+                self.inner.set(&key, &value)?;
+            }
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn test_close_flushes() -> Result<()> {
+        let mem = Memory::new();
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let buffered = BufferedStore {
+            inner: mem.share(),
+            pending: Vec::new(),
+            flushes: flushes.clone(),
+        };
+        let mut store = Box::new(PrefixedStore::new(buffered, b"p/".to_vec()));
+        store.set(b"a", b"1")?;
+        assert_eq!(mem.get(b"p/a")?, None);
+
+        store.close()?;
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
+        assert_eq!(mem.get(b"p/a")?, Some(b"1".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_load() -> Result<()> {
+        let mut source = Memory::new();
+        for i in 0..50u8 {
+            source.set(&[i], &vec![i; i as usize])?;
+        }
+        let mut buf = Vec::new();
+        assert_eq!(source.dump(&mut buf)?, 50);
+
+        let mut target = Memory::new();
+        assert_eq!(target.load(&mut buf.as_slice())?, 50);
+        let expect = source.iter_all().collect::<Result<Vec<_>>>()?;
+        assert_eq!(target.iter_all().collect::<Result<Vec<_>>>()?, expect);
+
+        // A dump cut off mid-pair is an error rather than a silent partial load.
+        let mut truncated = Memory::new();
+        assert!(truncated.load(&mut &buf[..buf.len() - 1]).is_err());
+        assert!(truncated.load(&mut &buf[..5]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tombstone_filter() -> Result<()> {
+        const TOMBSTONE: u8 = 0xff;
+        let mut mem = Memory::new();
+        for i in 0..10u8 {
+            let value = if i % 3 == 0 { vec![TOMBSTONE] } else { vec![i] };
+            mem.set(&[i], &value)?;
+        }
+
+        let is_tombstone = |value: &[u8]| value == [TOMBSTONE];
+        let keys = TombstoneFilter::new(mem.scan(Range::from(..)), is_tombstone)
+            .map(|item| item.map(|(k, _)| k[0]))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, vec![1, 2, 4, 5, 7, 8]);
+
+        let keys = TombstoneFilter::new(mem.scan(Range::from(..)), is_tombstone)
+            .rev()
+            .map(|item| item.map(|(k, _)| k[0]))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, vec![8, 7, 5, 4, 2, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytes() -> Result<()> {
+        let mut mem = Memory::new();
+        mem.set_bytes(Bytes::from("a"), Bytes::from("1"))?;
+        mem.set(b"b", b"")?;
+        for key in [&b"a"[..], b"b", b"c"] {
+            assert_eq!(
+                mem.get_bytes(key)?.map(|value| value.to_vec()),
+                mem.get(key)?
+            );
+        }
+        assert_eq!(mem.get_bytes(b"a")?, Some(Bytes::from("1")));
+        assert_eq!(mem.get_bytes(b"b")?, Some(Bytes::new()));
+        assert_eq!(mem.get_bytes(b"c")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_range() -> Result<()> {
+        let mut mem = Memory::new();
+        for i in 0..10u8 {
+            mem.set(&[i], &[i])?;
+        }
+        let range = || Range::from(vec![2]..vec![5]);
+        let expect = vec![(vec![2], vec![2]), (vec![3], vec![3]), (vec![4], vec![4])];
+        assert_eq!(mem.collect_range(range())?, expect);
+        assert_eq!(mem.collect_range_limited(range(), 3)?, expect);
+        assert!(mem.collect_range_limited(range(), 2).is_err());
+        assert!(mem.collect_range_limited(Range::from(..), 9).is_err());
+        assert_eq!(mem.collect_range_limited(Range::from(..), 10)?.len(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_peekable_scan() -> Result<()> {
+        let mut mem = Memory::new();
+        for i in 0..3u8 {
+            mem.set(&[i], &[i])?;
+        }
+        let peek = |scan: &mut PeekableScan| scan.peek().map(|item| item.as_ref().ok().cloned());
+        let mut scan = PeekableScan::new(mem.scan(Range::from(..)));
+        assert_eq!(peek(&mut scan), Some(Some((vec![0], vec![0]))));
+        assert_eq!(peek(&mut scan), Some(Some((vec![0], vec![0]))));
+        assert_eq!(scan.next().transpose()?, Some((vec![0], vec![0])));
+        assert_eq!(scan.next_back().transpose()?, Some((vec![2], vec![2])));
+        assert_eq!(peek(&mut scan), Some(Some((vec![1], vec![1]))));
+        // A peeked entry is still yielded from the back once the rest is consumed.
+        assert_eq!(scan.next_back().transpose()?, Some((vec![1], vec![1])));
+        assert!(scan.peek().is_none());
+        assert_eq!(scan.next().transpose()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_limit_is_lazy() -> Result<()> {
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let counter = pulled.clone();
+        let items = (0..100u8)
+            .map(|i| Ok((vec![i], vec![i])))
+            .collect::<Vec<_>>();
+        let inner: Scan = Box::new(items.into_iter().inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        let mut scan = ScanLimit::new(inner, 10, 50);
+        assert_eq!(scan.next().transpose()?, Some((vec![10], vec![10])));
+        assert_eq!(pulled.load(Ordering::SeqCst), 11);
+
+        // From the back, the rest of the window is read, but nothing past it.
+        assert_eq!(scan.next_back().transpose()?, Some((vec![59], vec![59])));
+        assert_eq!(pulled.load(Ordering::SeqCst), 60);
+        assert_eq!(scan.next().transpose()?, Some((vec![11], vec![11])));
+        assert_eq!(scan.count(), 47);
+
+        // An error ends the window, even while skipping.
+        let items = vec![
+            Ok((vec![0], vec![0])),
+            Err(anyhow!("boom")),
+            Ok((vec![1], vec![1])),
+        ];
+        let mut scan = ScanLimit::new(Box::new(items.into_iter()), 1, 5);
+        assert!(scan.next().unwrap().is_err());
+        assert!(scan.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff() -> Result<()> {
+        let mut a = Memory::new();
+        let mut b = Memory::new();
+        for i in 0..10u8 {
+            a.set(&[i], &[i])?;
+            b.set(&[i], &[i])?;
+        }
+        a.set(&[20], &[20])?;
+        a.delete(&[0])?;
+        b.delete(&[5])?;
+        b.set(&[3], b"changed")?;
+        b.set(&[15], &[15])?;
+
+        let ops = diff(&a, &b)?;
+        assert_eq!(
+            ops,
+            vec![
+                WriteOp::Set(vec![0], vec![0]),
+                WriteOp::Set(vec![3], b"changed".to_vec()),
+                WriteOp::Delete(vec![5]),
+                WriteOp::Set(vec![15], vec![15]),
+                WriteOp::Delete(vec![20]),
+            ]
+        );
+        a.apply_diff(ops)?;
+        assert_eq!(
+            a.collect_range(Range::from(..))?,
+            b.collect_range(Range::from(..))?
+        );
+        assert_eq!(diff(&a, &b)?, vec![]);
+        Ok(())
+    }
 }
@@ -1,78 +1,453 @@
 use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::collections::HashSet;
-use std::iter::Peekable;
 use std::ops::{Bound, RangeBounds};
 use std::path::Iter;
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use std::thread;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use serde_derive::{Deserialize, Serialize};
-use serde::{Serialize, Deserialize, Serializer};
 
-use super::{Scan, Store, Range};
+use crate::error::Error;
 
+use super::{PeekableScan, Range, Scan, Store};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 
+
+/// Lock order: the store's RwLock is the only lock MVCC takes, and it isn't reentrant, so
+/// every method acquires it at most once and never calls back into anything that locks it
+/// again. Helpers that need the store while it's held (check_dirty, Snapshot::take and
+/// Snapshot::restore) are handed the caller's guard. Scans returned to callers don't keep the
+/// guard, so a transaction can write while one of its scans is still open.
 pub struct MVCC {
-    stroe: Arc<RwLock<Box<dyn Store>>>
+    stroe: Arc<RwLock<Box<dyn Store>>>,
+    /// Whether transactions begun here flush the store when they commit.
+    sync: bool,
+    /// How many times `transact` retries a transaction that hit a conflict.
+    max_retries: u32,
 }
 
+/// The number of retries `transact` makes by default.
+const DEFAULT_MAX_RETRIES: u32 = 5;
+/// The backoff before the first retry in `transact`. It doubles with every retry, up to
+/// `MAX_RETRY_BACKOFF`.
+const RETRY_BACKOFF: Duration = Duration::from_millis(1);
+/// The longest backoff between retries in `transact`, however many retries are allowed.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
 impl Clone for MVCC {
     fn clone(&self) -> Self {
-        Self {stroe: self.stroe.clone()}
+        Self {
+            stroe: self.stroe.clone(),
+            sync: self.sync,
+            max_retries: self.max_retries,
+        }
     }
 }
 
 impl MVCC {
     pub fn new(store: Box<dyn Store>) -> Self {
-        Self{stroe: Arc::new(RwLock::new(store))}
+        Self {
+            stroe: Arc::new(RwLock::new(store)),
+            sync: true,
+            max_retries: DEFAULT_MAX_RETRIES,
+        }
+    }
+
+    /// Sets whether commits flush the store, which is the default. Without it, a commit is
+    /// visible to other transactions straight away but only durable after a later flush, so a
+    /// crash can lose it. That trades durability for latency, e.g. for a bulk import that
+    /// calls `flush` once at the end.
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Begins a new transaction in the given mode.
+    pub fn begin(&self, mode: Mode) -> Result<Transaction> {
+        let mut txn = Transaction::begin(self.stroe.clone(), mode)?;
+        txn.sync = self.sync;
+        Ok(txn)
+    }
+
+    /// Sets how many times `transact` retries a transaction after a conflict.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Runs `f` in a read-write transaction and commits it. If `f` or the commit fails with
+    /// `Error::Conflict`, the transaction is rolled back and retried with exponential backoff,
+    /// up to the configured number of retries, after which the conflict is returned. Any other
+    /// error rolls back and returns straight away. `f` may run several times, so it shouldn't
+    /// have side effects outside the transaction.
+    pub fn transact<T>(&self, f: impl Fn(&mut Transaction) -> Result<T>) -> Result<T> {
+        let mut retries = 0;
+        loop {
+            let mut txn = self.begin(Mode::ReadWrite)?;
+            let result = match f(&mut txn) {
+                Ok(value) => txn.commit().map(|_| value),
+                Err(err) => {
+                    // Return f's error, which is what the caller can act on, even if the
+                    // rollback fails too.
+                    let _ = txn.rollback();
+                    Err(err)
+                }
+            };
+            match result {
+                Err(err) if is_conflict(&err) && retries < self.max_retries => {
+                    thread::sleep(retry_backoff(retries));
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Begins a read-only transaction pinned to a snapshot taken now, which it shares its ID
+    /// with. All of its reads see the same data, even as other transactions commit, and
+    /// `begin_snapshot_at` with its ID can begin others at the same snapshot later.
+    pub fn begin_snapshot_now(&self) -> Result<Transaction> {
+        let store = self.stroe.clone();
+        let mut txn = Transaction::begin_with(store, |id| Mode::Snapshot { version: id })?;
+        txn.sync = self.sync;
+        Ok(txn)
+    }
+
+    /// Begins a read-only transaction pinned to the snapshot taken when the transaction with
+    /// the given ID began. Errors if there is no such snapshot.
+    pub fn begin_snapshot_at(&self, version: u64) -> Result<Transaction> {
+        self.begin(Mode::Snapshot { version })
+    }
+
+    /// Calls `f` with the underlying store, e.g. to downcast it with `Store::as_any` for methods
+    /// of the concrete store. The store is locked for reads meanwhile, so `f` must not use
+    /// this MVCC store.
+    pub fn with_store<R>(&self, f: impl FnOnce(&dyn Store) -> R) -> R {
+        f(&**self.stroe.read().unwrap())
+    }
+
+    /// Flushes the store, making all commits so far durable.
+    pub fn flush(&self) -> Result<()> {
+        self.stroe.write().unwrap().flush()
+    }
+
+    /// Creates an MVCC store whose transaction IDs start at `base`, so that shards can be given
+    /// disjoint ID ranges. IDs are issued monotonically from there. The base only applies to a
+    /// fresh store: if it has already issued IDs, it carries on from the last one.
+    pub fn new_with_id_base(mut store: Box<dyn Store>, base: u64) -> Result<Self> {
+        if base == 0 {
+            return Err(anyhow!("Transaction ID base must be at least 1"));
+        }
+        if store.get(&Key::TxnNext.encode())?.is_none() {
+            store.set(&Key::TxnNext.encode(), &serialize(&base)?)?;
+        }
+        Ok(Self::new(store))
+    }
+
+    /// Returns the IDs and modes of all active transactions, in ID order.
+    pub fn active_transactions(&self) -> Result<Vec<(u64, Mode)>> {
+        let session = self.stroe.read().unwrap();
+        scan_active(&**session)
+    }
+
+    /// Returns every record version written after `version` by a committed transaction, as the
+    /// user key, the version and the value, or None for a deletion. They're in version order,
+    /// and in key order within a version. Transactions still active are left out, as they may
+    /// roll back, but they can commit later with versions below ones already returned, so a
+    /// change stream should resume from before the oldest active transaction, not from the
+    /// highest version it has seen.
+    pub fn changes_since(&self, version: u64) -> Result<Vec<(Vec<u8>, u64, Option<Vec<u8>>)>> {
+        let session = self.stroe.read().unwrap();
+        let active: HashSet<u64> = scan_active(&**session)?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        let mut scan = session.scan(Range::from(Key::Record(vec![].into(), 0).encode()..));
+        let mut changes = Vec::new();
+        while let Some((k, v)) = scan.next().transpose()? {
+            match Key::decode(k)? {
+                Key::Record(key, record_version)
+                    if record_version > version && !active.contains(&record_version) =>
+                {
+                    changes.push((key.into_owned(), record_version, decode_value(&v)?))
+                }
+                Key::Record(..) => {}
+                k => return Err(anyhow!(format!("Expected Record, got {:?}", k))),
+            };
+        }
+        // The scan is in key order, which the stable sort keeps within a version.
+        changes.sort_by_key(|(_, version, _)| *version);
+        Ok(changes)
+    }
+
+    /// Reads a key as of the given version, as seen by the transaction with that ID: the latest
+    /// version of the key visible to its snapshot. Errors if there's no such snapshot.
+    pub fn get_as_of(&self, key: &[u8], version: u64) -> Result<Option<Vec<u8>>> {
+        let session = self.stroe.read().unwrap();
+        let snapshot = Snapshot::restore(&**session, version)?;
+        let mut scan = session.scan(Range::from(
+            Key::Record(key.into(), 0).encode()..=Key::Record(key.into(), version).encode(),
+        ));
+        let mut latest = None;
+        while let Some((k, v)) = scan.next().transpose()? {
+            match Key::decode(k)? {
+                Key::Record(k, version) if *k == *key && snapshot.is_visible(version) => {
+                    latest = Some(v)
+                }
+                Key::Record(..) => {}
+                k => return Err(anyhow!("Expected Txn::Record, got {:?}", k)),
+            };
+        }
+        match latest {
+            Some(v) => decode_value(&v),
+            None => Ok(None),
+        }
     }
+
+    /// Returns the ID of the oldest active transaction, if any.
+    pub fn oldest_active(&self) -> Result<Option<u64>> {
+        Ok(self.active_transactions()?.first().map(|(id, _)| *id))
+    }
+
+    /// Returns the version below which record versions may be garbage collected. It's the
+    /// lowest of the next transaction ID, the ID of every active transaction, whose writes may
+    /// still be rolled back, and the lowest version any active snapshot can't see. So every
+    /// record version below it was written by a committed transaction and is visible to all
+    /// active transactions.
+    pub fn gc_safe_point(&self) -> Result<u64> {
+        let active = self.active_transactions()?;
+        let session = self.stroe.read().unwrap();
+        let mut safe_point = match session.get(&Key::TxnNext.encode())? {
+            Some(ref v) => deserialize(v)?,
+            None => 1,
+        };
+        for (id, mode) in active {
+            let snapshot = match mode {
+                Mode::Snapshot { version } => Snapshot::restore(&**session, version)?,
+                _ => Snapshot::restore(&**session, id)?,
+            };
+            let oldest_invisible = snapshot.invisible.iter().min().cloned().unwrap_or(u64::MAX);
+            safe_point = safe_point
+                .min(id)
+                .min(snapshot.version + 1)
+                .min(oldest_invisible);
+        }
+        Ok(safe_point)
+    }
+
+    /// Garbage collects record versions older than `before` that have been superseded by a
+    /// newer version also older than `before`. Errors if `before` is past the safe point, since
+    /// an active transaction may still need those versions. Returns the number removed.
+    pub fn gc(&self, before: u64) -> Result<u64> {
+        let safe_point = self.gc_safe_point()?;
+        if before > safe_point {
+            return Err(anyhow!(format!(
+                "GC version {} is past the safe point {}",
+                before, safe_point
+            )));
+        }
+        let mut session = self.stroe.write().unwrap();
+        let mut obsolete = Vec::new();
+        let mut scan = VersionGroupScan::new(
+            session.scan(Range::from(Key::Record(vec![].into(), 0).encode()..)),
+        );
+        while let Some((key, versions)) = scan.next().transpose()? {
+            // Versions are ordered oldest first, so each one older than `before` is superseded
+            // by the next, except the last.
+            let old = versions
+                .iter()
+                .filter(|(version, _)| *version < before)
+                .count();
+            for (version, _) in versions.iter().take(old.saturating_sub(1)) {
+                obsolete.push(Key::Record(Cow::from(&key[..]), *version).encode());
+            }
+        }
+        std::mem::drop(scan);
+        for key in obsolete.iter() {
+            session.delete(key)?;
+        }
+        Ok(obsolete.len() as u64)
+    }
+}
+
+/// Returns the IDs and modes of all active transactions, in ID order.
+fn scan_active(session: &dyn Store) -> Result<Vec<(u64, Mode)>> {
+    let mut scan = session.scan(Range::from(
+        Key::TxnActive(0).encode()..=Key::TxnActive(std::u64::MAX).encode(),
+    ));
+    let mut active = Vec::new();
+    while let Some((key, value)) = scan.next().transpose()? {
+        match Key::decode(key)? {
+            Key::TxnActive(id) => active.push((id, deserialize(&value)?)),
+            k => return Err(anyhow!(format!("Expected TxnActive, got {:?}", k))),
+        };
+    }
+    Ok(active)
 }
 
 /// Serializes MVCC metadata.
-fn serialize<V: Serialize>(value: &V) -> Result<Vec<u8>> {
+fn serialize<V: serde::Serialize>(value: &V) -> Result<Vec<u8>> {
     Ok(bincode::serialize(value)?)
 }
 
 /// Deserializes MVCC metadata.
-fn deserialize<'a, V: Deserialize<'a>>(bytes: &'a [u8]) -> Result<V> {
+fn deserialize<'a, V: serde::Deserialize<'a>>(bytes: &'a [u8]) -> Result<V> {
     Ok(bincode::deserialize(bytes)?)
 }
 
+/// Returns the backoff before a retry in `transact`, after `retries` earlier retries.
+fn retry_backoff(retries: u32) -> Duration {
+    RETRY_BACKOFF
+        .saturating_mul(2u32.saturating_pow(retries))
+        .min(MAX_RETRY_BACKOFF)
+}
+
+/// Returns true if the error is a serialization conflict, i.e. `Error::Conflict`.
+fn is_conflict(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<Error>(), Some(Error::Conflict))
+}
+
+/// Encodes a record value as a tag byte, 0x00 for a tombstone or 0x01 for a value, followed
+/// by the raw value bytes.
+fn encode_value(value: Option<&[u8]>) -> Vec<u8> {
+    match value {
+        Some(value) => {
+            let mut bytes = Vec::with_capacity(value.len() + 1);
+            bytes.push(0x01);
+            bytes.extend_from_slice(value);
+            bytes
+        }
+        None => vec![0x00],
+    }
+}
+
+/// Maps a range of user keys to the range of their record keys, across all versions.
+fn record_range(range: impl RangeBounds<Vec<u8>>) -> Range {
+    let start = match range.start_bound() {
+        Bound::Excluded(k) => {
+            Bound::Excluded(Key::Record(k.to_vec().into(), std::u64::MAX).encode())
+        }
+        Bound::Included(k) => Bound::Included(Key::Record(k.to_vec().into(), 0).encode()),
+        Bound::Unbounded => Bound::Included(Key::Record(vec![].into(), 0).encode()),
+    };
+    let end = match range.end_bound() {
+        Bound::Excluded(k) => Bound::Excluded(Key::Record(k.to_vec().into(), 0).encode()),
+        Bound::Included(k) => {
+            Bound::Included(Key::Record(k.to_vec().into(), std::u64::MAX).encode())
+        }
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    Range::from((start, end))
+}
+
+/// Checks whether a record value is a tombstone from its tag, without decoding it.
+fn is_tombstone(bytes: &[u8]) -> Result<bool> {
+    match bytes.split_first() {
+        Some((0x00, [])) => Ok(true),
+        Some((0x01, _)) => Ok(false),
+        _ => Err(anyhow!("Invalid record value encoding")),
+    }
+}
+
+/// Decodes a record value, returning None for a tombstone.
+fn decode_value(bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+    match bytes.split_first() {
+        Some((0x00, [])) => Ok(None),
+        Some((0x01, value)) => Ok(Some(value.to_vec())),
+        _ => Err(anyhow!("Invalid record value encoding")),
+    }
+}
+
 
 pub struct Transaction {
     store: Arc<RwLock<Box<dyn Store>>>,
     id: u64,
     mode: Mode,
     snapshot: Snapshot,
+    counters: Arc<TxnCounters>,
+    /// Set once the transaction has been committed or rolled back. Transactions dropped
+    /// without either are rolled back, so an early return can't leak their writes.
+    finished: bool,
+    /// Whether commit flushes the store. See `MVCC::with_sync`.
+    sync: bool,
+}
+
+/// Counts of the keys a transaction has read and written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TxnStats {
+    /// Keys fetched by get, plus rows yielded by scans.
+    pub reads: u64,
+    /// Keys set.
+    pub writes: u64,
+    /// Keys deleted.
+    pub deletes: u64,
+}
+
+/// Shared with scan iterators, which count rows as they are yielded.
+#[derive(Default)]
+struct TxnCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    deletes: AtomicU64,
 }
 
 impl Transaction {
     /// Begins a new transaction in the given mode.
     fn begin(store: Arc<RwLock<Box<dyn Store>>>, mode: Mode) -> Result<Self> {
+        Self::begin_with(store, |_| mode)
+    }
+
+    /// Begins a new transaction in the mode returned for its ID.
+    fn begin_with(
+        store: Arc<RwLock<Box<dyn Store>>>,
+        mode: impl FnOnce(u64) -> Mode,
+    ) -> Result<Self> {
         let mut session = store.write().unwrap();
 
         let id = match session.get(&Key::TxnNext.encode())? {
             Some(ref v) => deserialize(v)?,
             None => 1,
         };
+        let mode = mode(id);
+        // Check that a snapshot to pin exists before recording the transaction, so that a bad
+        // version doesn't leave it active. A snapshot at its own ID is taken below.
+        let pinned = match &mode {
+            Mode::Snapshot { version } if *version != id => {
+                Some(Snapshot::restore(&**session, *version)?)
+            }
+            _ => None,
+        };
         session.set(Key::TxnNext.encode().to_owned(), serialize(&(id + 1))?.into())?;
         session.set(Key::TxnActive(id).encode().to_owned(), serialize(&mode)?.into())?;
+        trace_event!(tracing::Level::DEBUG, txn_id = id, mode = ?mode, "begin");
 
         // We always take a new snapshot, even for snapshot transactions, because all transactions
         // increment the transaction ID and we need to properly record currently active transactions
         // for any future snapshot transactions looking at this one.
         let mut snapshot = Snapshot::take(&mut session, id)?;
-        std::mem::drop(session);
-        if let Mode::Snapshot { version } = &mode {
-            snapshot = Snapshot::restore(&store.read().unwrap(), *version)?
+        if let Some(pinned) = pinned {
+            snapshot = pinned;
         }
+        std::mem::drop(session);
 
-        Ok(Self { store, id, mode, snapshot })
+        Ok(Self {
+            store,
+            id,
+            mode,
+            snapshot,
+            counters: Arc::default(),
+            finished: false,
+            sync: true,
+        })
     }
 
     /// Resumes an active transaction with the given ID. Errors if the transaction is not active.
+    /// As with any transaction, dropping the resumed handle without committing rolls it back.
     fn resume(store: Arc<RwLock<Box<dyn Store>>>, id: u64) -> Result<Self> {
         let session = store.read().unwrap();
         let mode = match session.get(&Key::TxnActive(id).encode())? {
@@ -80,11 +455,19 @@ impl Transaction {
             None => return Err(anyhow!(format!("No active transaction {}", id))),
         };
         let snapshot = match &mode {
-            Mode::Snapshot { version } => Snapshot::restore(&session, *version)?,
-            _ => Snapshot::restore(&session, id)?,
+            Mode::Snapshot { version } => Snapshot::restore(&**session, *version)?,
+            _ => Snapshot::restore(&**session, id)?,
         };
         std::mem::drop(session);
-        Ok(Self { store, id, mode, snapshot })
+        Ok(Self {
+            store,
+            id,
+            mode,
+            snapshot,
+            counters: Arc::default(),
+            finished: false,
+            sync: true,
+        })
     }
 
     /// Returns the transaction ID.
@@ -97,16 +480,49 @@ impl Transaction {
         self.mode
     }
 
-    /// Commits the transaction, by removing the txn from the active set.
+    /// Returns the number of keys read and written so far.
+    pub fn stats(&self) -> TxnStats {
+        TxnStats {
+            reads: self.counters.reads.load(AtomicOrdering::Relaxed),
+            writes: self.counters.writes.load(AtomicOrdering::Relaxed),
+            deletes: self.counters.deletes.load(AtomicOrdering::Relaxed),
+        }
+    }
+
+    /// Commits the transaction, by removing the txn from the active set. Flushes the store
+    /// unless the MVCC store was configured without sync.
     pub fn commit(self) -> Result<()> {
+        let sync = self.sync;
+        self.commit_with_flush(sync)
+    }
+
+    /// Commits the transaction without flushing the store, so that it isn't durable until the
+    /// next flush. See `MVCC::with_sync`.
+    pub fn commit_no_flush(self) -> Result<()> {
+        self.commit_with_flush(false)
+    }
+
+    fn commit_with_flush(mut self, flush: bool) -> Result<()> {
         let mut session = self.store.write().unwrap();
         session.delete(&Key::TxnActive(self.id).encode())?;
-        session.flush()
+        trace_event!(tracing::Level::DEBUG, txn_id = self.id, flush, "commit");
+        self.finished = true;
+        if flush {
+            session.flush()?;
+        }
+        Ok(())
     }
 
     /// Rolls back the transaction, by removing all updated entries.
-    pub fn rollback(self) -> Result<()> {
+    pub fn rollback(mut self) -> Result<()> {
+        self.finished = true;
+        self.rollback_writes()
+    }
+
+    /// Removes all updated entries and the active marker. Shared by rollback and drop.
+    fn rollback_writes(&self) -> Result<()> {
         let mut session = self.store.write().unwrap();
+        trace_event!(tracing::Level::DEBUG, txn_id = self.id, "rollback");
         if self.mode.mutable() {
             let mut rollback = Vec::new();
             let mut scan = session.scan(Range::from(
@@ -133,41 +549,89 @@ impl Transaction {
         self.write(key, None)
     }
 
+    /// Deletes a key, returning `Error::NotFound` if it isn't visible to the transaction,
+    /// including when the transaction deleted it itself.
+    pub fn delete_existing(&mut self, key: &[u8]) -> Result<()> {
+        if self.get(&Bytes::copy_from_slice(key))?.is_none() {
+            return Err(Error::NotFound(key.to_vec()).into());
+        }
+        self.write(key, None)
+    }
+
     /// Fetches a key.
     pub fn get(&self, key: &Bytes) -> Result<Option<Vec<u8>>> {
+        self.counters.reads.fetch_add(1, AtomicOrdering::Relaxed);
         let session = self.store.read().unwrap();
         let mut scan = session
             .scan(Range::from(
                 Key::Record(key.to_vec().into(), 0).encode()
                     ..=Key::Record(key.to_vec().into(), self.id).encode(),
             ));
-        while let Some((k, v)) = scan.next().transpose()? {
+        // Look for the latest visible version, skipping longer keys that share the prefix.
+        while let Some((k, v)) = scan.next_back().transpose()? {
             match Key::decode(k)? {
-                Key::Record(_, version) => {
-                    if self.snapshot.is_visible(version) {
-                        return deserialize(&v);
-                    }
+                Key::Record(k, version) if *k == key[..] && self.snapshot.is_visible(version) => {
+                    return decode_value(&v);
                 }
+                Key::Record(..) => {}
                 k => return Err(anyhow!("Expected Txn::Record, got {:?}", k)),
             };
         }
         Ok(None)
     }
 
+    /// Returns every version of a key in version order, with None for deletions, regardless
+    /// of whether they're visible to this transaction. Used for auditing.
+    pub fn get_history(&self, key: &[u8]) -> Result<Vec<(u64, Option<Vec<u8>>)>> {
+        let session = self.store.read().unwrap();
+        let mut scan = session.scan(Range::from(
+            Key::Record(key.into(), 0).encode()..=Key::Record(key.into(), std::u64::MAX).encode(),
+        ));
+        let mut history = Vec::new();
+        while let Some((k, v)) = scan.next().transpose()? {
+            match Key::decode(k)? {
+                // Longer keys with this key as a prefix can sort among its versions.
+                Key::Record(k, version) if *k == *key => history.push((version, decode_value(&v)?)),
+                Key::Record(..) => {}
+                k => return Err(anyhow!("Expected Txn::Record, got {:?}", k)),
+            };
+        }
+        Ok(history)
+    }
+
     /// Scans a key range.
     pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<super::Scan> {
-        let start = match range.start_bound() {
-            Bound::Excluded(k) => Bound::Excluded(Key::Record(k.to_vec().into(), std::u64::MAX).encode()),
-            Bound::Included(k) => Bound::Included(Key::Record(k.to_vec().into(), 0).encode()),
-            Bound::Unbounded => Bound::Included(Key::Record(vec![].into(), 0).encode()),
-        };
-        let end = match range.end_bound() {
-            Bound::Excluded(k) => Bound::Excluded(Key::Record(k.to_vec().into(), 0).encode()),
-            Bound::Included(k) => Bound::Included(Key::Record(k.to_vec().into(), std::u64::MAX).encode()),
-            Bound::Unbounded => Bound::Unbounded,
-        };
-        let scan = self.store.read().unwrap().scan(Range::from((start, end)));
-        Ok(Box::new(KeyScan::new(scan, self.snapshot.clone())))
+        let scan = self.store.read().unwrap().scan(record_range(range));
+        let counters = self.counters.clone();
+        Ok(Box::new(KeyScan::new(scan, self.snapshot.clone()).inspect(
+            move |item| {
+                if item.is_ok() {
+                    counters.reads.fetch_add(1, AtomicOrdering::Relaxed);
+                }
+            },
+        )))
+    }
+
+    /// Scans the keys of a key range. Visibility and deletions are determined from the record
+    /// keys and the tombstone tag of the values, without decoding the values.
+    pub fn scan_keys(&self, range: impl RangeBounds<Vec<u8>>) -> Result<super::Keys> {
+        let scan = self.store.read().unwrap().scan(record_range(range));
+        let counters = self.counters.clone();
+        Ok(Box::new(
+            KeyScan::new(scan, self.snapshot.clone())
+                .keys()
+                .inspect(move |item| {
+                    if item.is_ok() {
+                        counters.reads.fetch_add(1, AtomicOrdering::Relaxed);
+                    }
+                }),
+        ))
+    }
+
+    /// Counts the visible keys in a key range, like `scan_keys`.
+    pub fn count(&self, range: impl RangeBounds<Vec<u8>>) -> Result<u64> {
+        self.scan_keys(range)?
+            .try_fold(0, |count, key| key.map(|_| count + 1))
     }
 
     /// Scans keys under a given prefix.
@@ -199,15 +663,16 @@ impl Transaction {
         self.write(key, Some(value.to_vec()))
     }
 
-    /// Writes a value for a key. None is used for deletion.
-    fn write(&self, key: &[u8], value: Option<Vec<u8>>) -> Result<()> {
-        if !self.mode.mutable() {
-            return Err(anyhow!("Read Only"));
-        }
-        let mut session = self.store.write().unwrap();
+    /// Returns true if the key has uncommitted changes from a concurrent transaction, in which
+    /// case writing it would fail with a serialization error.
+    pub fn is_dirty(&self, key: &[u8]) -> Result<bool> {
+        let session = self.store.read().unwrap();
+        self.check_dirty(&**session, key)
+    }
 
-        // Check if the key is dirty, i.e. if it has any uncommitted changes, by scanning for any
-        // versions that aren't visible to us.
+    /// Checks if the key is dirty by scanning for any versions that aren't visible to us. Takes
+    /// the store from the caller, so that write can check under the lock it writes with.
+    fn check_dirty(&self, session: &dyn Store, key: &[u8]) -> Result<bool> {
         let min = self.snapshot.invisible.iter().min().cloned().unwrap_or(self.id + 1);
         let mut scan = session
             .scan(Range::from(
@@ -218,19 +683,56 @@ impl Transaction {
             match Key::decode(k)? {
                 Key::Record(_, version) => {
                     if !self.snapshot.is_visible(version) {
-                        return Err(anyhow!("Serialize error"));
+                        return Ok(true);
                     }
                 }
                 k => return Err(anyhow!(format!("Expected Txn::Record, got {:?}", k))),
             };
         }
-        std::mem::drop(scan);
+        Ok(false)
+    }
+
+    /// Writes a value for a key. None is used for deletion. The dirty check and the writes
+    /// happen under one write guard, so no other transaction can write the key in between.
+    fn write(&self, key: &[u8], value: Option<Vec<u8>>) -> Result<()> {
+        if !self.mode.mutable() {
+            return Err(anyhow!("Read Only"));
+        }
+        let mut session = self.store.write().unwrap();
+
+        let conflict = self.check_dirty(&**session, key)?;
+        trace_event!(
+            tracing::Level::DEBUG,
+            txn_id = self.id,
+            key_len = key.len(),
+            delete = value.is_none(),
+            conflict,
+            "write"
+        );
+        if conflict {
+            return Err(Error::Conflict.into());
+        }
 
         // Write the key and its update record.
         let key = Key::Record(key.to_vec().into(), self.id).encode();
         let update = Key::TxnUpdate(self.id, key.to_vec().into()).encode();
         session.set(update, Bytes::new())?;
-        session.set(key, serialize(&value)?.into())
+        session.set(key, &encode_value(value.as_deref()))?;
+        match value {
+            Some(_) => self.counters.writes.fetch_add(1, AtomicOrdering::Relaxed),
+            None => self.counters.deletes.fetch_add(1, AtomicOrdering::Relaxed),
+        };
+        Ok(())
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        // Best effort: there's no way to report an error here, and a poisoned lock means a
+        // writer panicked, so the store can't be trusted for a rollback anyway.
+        if !self.finished && !self.store.is_poisoned() {
+            let _ = self.rollback_writes();
+        }
     }
 }
 
@@ -282,7 +784,7 @@ impl Snapshot {
         Ok(snapshot)
     }
 
-    fn restore(session: &RwLockReadGuard<Box<dyn Store>>, version: u64) -> Result<Self> {
+    fn restore(session: &dyn Store, version: u64) -> Result<Self> {
         match session.get(&Key::TxnSnapshot(version).encode())? {
             Some(ref v) => Ok(Self { version, invisible: deserialize(v)? }),
             None => Err(anyhow!(format!("Snapshot not found for version {}", version))),
@@ -368,21 +870,1064 @@ impl<'a> Key<'a> {
     }
 }
 
+/// A versioned record key as MVCC stores it, for code that shares the underlying store and
+/// needs to parse or build record keys, e.g. secondary indexes. Record keys are 0xff followed
+/// by the user key and the big-endian version, so the versions of a user key sort in order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordKey {
+    pub user_key: Vec<u8>,
+    pub version: u64,
+}
+
+impl RecordKey {
+    pub fn new(user_key: impl Into<Vec<u8>>, version: u64) -> Self {
+        Self {
+            user_key: user_key.into(),
+            version,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        Key::Record(Cow::from(&self.user_key[..]), self.version)
+            .encode()
+            .to_vec()
+    }
+
+    /// Decodes a record key, erroring on any other key MVCC stores (or any other bytes).
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 9 || bytes[0] != 0xff {
+            return Err(anyhow!("Invalid record key {:?}", bytes));
+        }
+        match Key::decode(bytes.to_vec().into())? {
+            Key::Record(user_key, version) => Ok(Self {
+                user_key: user_key.into_owned(),
+                version,
+            }),
+            key => Err(anyhow!("Expected record key, got {:?}", key)),
+        }
+    }
+}
+
+/// Groups adjacent record versions by user key in a single pass over a scan of raw records,
+/// yielding each user key with its versions, oldest first, and whether each is a tombstone.
+/// Records of a longer key sharing a key's prefix can sort among its versions, in which case
+/// the key's versions are split across several groups.
+struct VersionGroupScan {
+    scan: Scan,
+    /// The first record of the next group, read while looking for the end of the last one.
+    pending: Option<(Vec<u8>, u64, bool)>,
+}
+
+impl VersionGroupScan {
+    fn new(scan: Scan) -> Self {
+        Self {
+            scan,
+            pending: None,
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<(Vec<u8>, u64, bool)>> {
+        match self.scan.next().transpose()? {
+            Some((k, v)) => match Key::decode(k)? {
+                Key::Record(key, version) => {
+                    Ok(Some((key.into_owned(), version, is_tombstone(&v)?)))
+                }
+                k => Err(anyhow!(format!("Expected Record, got {:?}", k))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn try_next(&mut self) -> Result<Option<(Vec<u8>, Vec<(u64, bool)>)>> {
+        let (key, version, tombstone) = match self.pending.take() {
+            Some(record) => record,
+            None => match self.next_record()? {
+                Some(record) => record,
+                None => return Ok(None),
+            },
+        };
+        let mut versions = vec![(version, tombstone)];
+        while let Some((next_key, version, tombstone)) = self.next_record()? {
+            if next_key != key {
+                self.pending = Some((next_key, version, tombstone));
+                break;
+            }
+            versions.push((version, tombstone));
+        }
+        Ok(Some((key, versions)))
+    }
+}
+
+impl Iterator for VersionGroupScan {
+    type Item = Result<(Vec<u8>, Vec<(u64, bool)>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
 
 pub struct KeyScan {
-    scan: Peekable<Scan>
+    scan: PeekableScan,
+    /// The key last yielded or skipped from the back. Its older versions are still in the scan
+    /// and must be skipped from both ends.
+    last_back: Option<Vec<u8>>,
 }
 
 impl KeyScan {
     fn new(mut scan: Scan, snapshot: Snapshot) -> Self {
+        // Drop versions invisible to the snapshot up front, so that peeking at the next entry
+        // tells us whether the current one is the latest visible version of its key.
+        scan = Box::new(scan.filter_map(move |item| {
+            item.and_then(|(k, v)| match Key::decode(k.clone())? {
+                Key::Record(_, version) if !snapshot.is_visible(version) => Ok(None),
+                Key::Record(..) => Ok(Some((k, v))),
+                k => Err(anyhow!(format!("Expected Record, got {:?}", k))),
+            })
+            .transpose()
+        }));
+        Self {
+            scan: PeekableScan::new(scan),
+            last_back: None,
+        }
+    }
 
-        Self { scan: scan.peekable()}
+    /// Returns a scan of the keys only, which skips deleted keys by their tombstone tag
+    /// without decoding the values.
+    fn keys(self) -> KeyOnlyScan {
+        KeyOnlyScan(self)
+    }
+
+    fn try_next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        while let Some((key, v)) = self.try_next_latest()? {
+            if let Some(value) = decode_value(&v)? {
+                return Ok(Some((key, value)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn try_next_back(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        while let Some((key, v)) = self.try_next_back_latest()? {
+            if let Some(value) = decode_value(&v)? {
+                return Ok(Some((key, value)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the next key with its latest visible record value, which may be a tombstone.
+    fn try_next_latest(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        while let Some((k, v)) = self.scan.next().transpose()? {
+            let key = match Key::decode(k)? {
+                Key::Record(key, _) => key.into_owned(),
+                k => return Err(anyhow!(format!("Expected Record, got {:?}", k))),
+            };
+            let is_latest = match self.scan.peek() {
+                Some(Ok((peek, _))) => match Key::decode(peek.clone())? {
+                    Key::Record(peek_key, _) => *peek_key != *key,
+                    k => return Err(anyhow!(format!("Expected Record, got {:?}", k))),
+                },
+                Some(Err(_)) | None => true,
+            };
+            // If the back already got to this key, the newer versions were consumed there.
+            if is_latest && self.last_back.as_ref() != Some(&key) {
+                return Ok(Some((key, v)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Like `try_next_latest`, from the back, where the first version seen of each key is the
+    /// latest visible one.
+    fn try_next_back_latest(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        while let Some((k, v)) = self.scan.next_back().transpose()? {
+            let key = match Key::decode(k)? {
+                Key::Record(key, _) => key.into_owned(),
+                k => return Err(anyhow!(format!("Expected Record, got {:?}", k))),
+            };
+            if self.last_back.as_ref() == Some(&key) {
+                continue;
+            }
+            self.last_back = Some(key.clone());
+            return Ok(Some((key, v)));
+        }
+        Ok(None)
     }
 }
 
 impl Iterator for KeyScan {
-    type Item = Result<(Bytes, Bytes)>;
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
     fn next(&mut self) -> Option<Self::Item> {
-        todo!()
+        self.try_next().transpose()
+    }
+}
+
+impl DoubleEndedIterator for KeyScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.try_next_back().transpose()
+    }
+}
+
+/// See `KeyScan::keys`.
+struct KeyOnlyScan(KeyScan);
+
+impl KeyOnlyScan {
+    fn try_next(&mut self) -> Result<Option<Vec<u8>>> {
+        while let Some((key, v)) = self.0.try_next_latest()? {
+            if !is_tombstone(&v)? {
+                return Ok(Some(key));
+            }
+        }
+        Ok(None)
+    }
+
+    fn try_next_back(&mut self) -> Result<Option<Vec<u8>>> {
+        while let Some((key, v)) = self.0.try_next_back_latest()? {
+            if !is_tombstone(&v)? {
+                return Ok(Some(key));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for KeyOnlyScan {
+    type Item = Result<Vec<u8>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
+impl DoubleEndedIterator for KeyOnlyScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.try_next_back().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::memory::Memory;
+    use super::super::prefixed::PrefixedStore;
+    use super::*;
+    use rand::Rng;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_value_encoding() -> Result<()> {
+        assert_eq!(encode_value(Some(b"v")), vec![0x01, b'v']);
+        assert_eq!(encode_value(Some(b"")), vec![0x01]);
+        assert_eq!(encode_value(None), vec![0x00]);
+        assert_eq!(
+            decode_value(&encode_value(Some(b"v")))?,
+            Some(b"v".to_vec())
+        );
+        assert_eq!(decode_value(&encode_value(Some(b"")))?, Some(vec![]));
+        assert_eq!(decode_value(&encode_value(None))?, None);
+        assert!(decode_value(&[]).is_err());
+        assert!(decode_value(&[0x00, 0x01]).is_err());
+
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let mut txn = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+        txn.set(b"a", b"1".to_vec())?;
+        txn.set(b"b", b"2".to_vec())?;
+        txn.delete(&Bytes::from("b"))?;
+        assert_eq!(txn.get(&Bytes::from("a"))?, Some(b"1".to_vec()));
+        assert_eq!(txn.get(&Bytes::from("b"))?, None);
+
+        let scan = txn.scan(..)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(scan, vec![(b"a".to_vec(), b"1".to_vec())]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let mut txn = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+        txn.set(b"a", b"1".to_vec())?;
+        txn.set(b"b", b"2".to_vec())?;
+        txn.delete(&Bytes::from("a"))?;
+        txn.get(&Bytes::from("a"))?;
+        txn.get(&Bytes::from("b"))?;
+        assert_eq!(txn.scan(..)?.count(), 1);
+
+        let stats = txn.stats();
+        assert_eq!(
+            stats,
+            TxnStats {
+                reads: 3,
+                writes: 2,
+                deletes: 1
+            }
+        );
+        txn.rollback()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_dirty() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let mut t1 = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+        t1.set(b"a", b"1".to_vec())?;
+
+        let mut t2 = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+        assert!(t2.is_dirty(b"a")?);
+        assert!(!t2.is_dirty(b"b")?);
+        assert!(t2.set(b"a", b"2".to_vec()).is_err());
+        t2.set(b"b", b"2".to_vec())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_active_transactions() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        assert_eq!(mvcc.active_transactions()?, vec![]);
+        assert_eq!(mvcc.oldest_active()?, None);
+
+        let t1 = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+        let t2 = Transaction::begin(mvcc.stroe.clone(), Mode::ReadOnly)?;
+        let t3 = Transaction::begin(mvcc.stroe.clone(), Mode::Snapshot { version: 1 })?;
+        assert_eq!(
+            mvcc.active_transactions()?,
+            vec![
+                (t1.id(), Mode::ReadWrite),
+                (t2.id(), Mode::ReadOnly),
+                (t3.id(), Mode::Snapshot { version: 1 }),
+            ]
+        );
+        assert_eq!(mvcc.oldest_active()?, Some(t1.id()));
+
+        t1.commit()?;
+        assert_eq!(mvcc.oldest_active()?, Some(t2.id()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_safe_point() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let mut t1 = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+        t1.set(b"a", b"1".to_vec())?;
+        t1.commit()?;
+
+        let reader = Transaction::begin(mvcc.stroe.clone(), Mode::ReadOnly)?;
+        for value in [b"2", b"3"] {
+            let mut txn = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+            txn.set(b"a", value.to_vec())?;
+            txn.commit()?;
+        }
+
+        // The reader can see version 1 but not versions 3 and 4, so nothing can be collected.
+        assert_eq!(mvcc.gc_safe_point()?, 2);
+        assert!(mvcc.gc(5).is_err());
+        assert!(mvcc.gc(3).is_err());
+        assert_eq!(mvcc.gc(2)?, 0);
+        assert_eq!(reader.get(&Bytes::from("a"))?, Some(b"1".to_vec()));
+        reader.commit()?;
+
+        assert_eq!(mvcc.gc_safe_point()?, 5);
+        assert_eq!(mvcc.gc(5)?, 2);
+        let txn = Transaction::begin(mvcc.stroe.clone(), Mode::ReadOnly)?;
+        assert_eq!(txn.get(&Bytes::from("a"))?, Some(b"3".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_active_writer() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let mut t1 = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+        t1.set(b"a", b"1".to_vec())?;
+        t1.commit()?;
+
+        // An uncommitted write doesn't supersede the committed version, as it may roll back.
+        let mut t2 = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+        t2.set(b"a", b"2".to_vec())?;
+        assert_eq!(mvcc.gc_safe_point()?, t2.id());
+        assert!(mvcc.gc(t2.id() + 1).is_err());
+        assert_eq!(mvcc.gc(mvcc.gc_safe_point()?)?, 0);
+        t2.rollback()?;
+
+        let txn = Transaction::begin(mvcc.stroe.clone(), Mode::ReadOnly)?;
+        assert_eq!(txn.get(&Bytes::from("a"))?, Some(b"1".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_id_base() -> Result<()> {
+        let low = MVCC::new_with_id_base(Box::new(Memory::new()), 1)?;
+        let high = MVCC::new_with_id_base(Box::new(Memory::new()), 1_000_000)?;
+        let mut low_ids = Vec::new();
+        let mut high_ids = Vec::new();
+        for _ in 0..5 {
+            low_ids.push(Transaction::begin(low.stroe.clone(), Mode::ReadWrite)?.id());
+            high_ids.push(Transaction::begin(high.stroe.clone(), Mode::ReadWrite)?.id());
+        }
+        assert_eq!(low_ids, vec![1, 2, 3, 4, 5]);
+        assert_eq!(
+            high_ids,
+            vec![1_000_000, 1_000_001, 1_000_002, 1_000_003, 1_000_004]
+        );
+
+        // An existing store keeps issuing IDs from where it left off.
+        let store = Arc::try_unwrap(high.stroe)
+            .ok()
+            .unwrap()
+            .into_inner()
+            .unwrap();
+        let high = MVCC::new_with_id_base(store, 1)?;
+        assert_eq!(
+            Transaction::begin(high.stroe.clone(), Mode::ReadWrite)?.id(),
+            1_000_005
+        );
+        assert!(MVCC::new_with_id_base(Box::new(Memory::new()), 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_rolls_back() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let write = || -> Result<()> {
+            let mut txn = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+            txn.set(b"a", b"1".to_vec())?;
+            txn.set(b"b", b"2".to_vec())?;
+            Err(anyhow!("early return"))
+        };
+        assert!(write().is_err());
+        assert_eq!(mvcc.active_transactions()?, vec![]);
+
+        // Only the transaction ID counter and the snapshot are left behind.
+        let session = mvcc.stroe.read().unwrap();
+        for item in session.scan(Range::from(..)) {
+            let (key, _) = item?;
+            match Key::decode(key.into())? {
+                Key::TxnNext | Key::TxnSnapshot(_) => {}
+                k => panic!("Unexpected orphan key {:?}", k),
+            }
+        }
+        std::mem::drop(session);
+
+        let txn = Transaction::begin(mvcc.stroe.clone(), Mode::ReadOnly)?;
+        assert_eq!(txn.scan(..)?.count(), 0);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_history() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        for value in [b"1", b"2", b"3"] {
+            let mut txn = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+            txn.set(b"a", value.to_vec())?;
+            txn.set(b"a\0", b"other".to_vec())?;
+            txn.commit()?;
+        }
+        let mut txn = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+        txn.delete(&Bytes::from("a"))?;
+
+        // History ignores visibility, so it includes the uncommitted delete.
+        let reader = Transaction::begin(mvcc.stroe.clone(), Mode::ReadOnly)?;
+        assert_eq!(
+            reader.get_history(b"a")?,
+            vec![
+                (1, Some(b"1".to_vec())),
+                (2, Some(b"2".to_vec())),
+                (3, Some(b"3".to_vec())),
+                (4, None),
+            ]
+        );
+        assert_eq!(reader.get_history(b"b")?, vec![]);
+
+        assert_eq!(mvcc.get_as_of(b"a", 2)?, Some(b"2".to_vec()));
+        assert_eq!(mvcc.get_as_of(b"a", 4)?, None);
+        assert_eq!(mvcc.get_as_of(b"a", 5)?, Some(b"3".to_vec()));
+        assert!(mvcc.get_as_of(b"a", 9).is_err());
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_writes() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let (done, finished) = std::sync::mpsc::channel();
+        for t in 0..8u8 {
+            let store = mvcc.stroe.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let mut committed = 0;
+                for i in 0..50u8 {
+                    let mut txn = Transaction::begin(store.clone(), Mode::ReadWrite).unwrap();
+                    // Overlapping keys, so some writes hit serialization errors.
+                    let result = (0..3u8).try_for_each(|k| {
+                        txn.scan(..)?.count();
+                        txn.set(&[(i + k) % 10], vec![t])
+                    });
+                    match result {
+                        Ok(()) => {
+                            txn.commit().unwrap();
+                            committed += 1;
+                        }
+                        Err(_) => txn.rollback().unwrap(),
+                    }
+                }
+                done.send(committed).unwrap();
+            });
+        }
+        let mut committed = 0;
+        for _ in 0..8 {
+            committed += finished.recv_timeout(std::time::Duration::from_secs(30))?;
+        }
+        assert!(committed > 0);
+        assert_eq!(mvcc.active_transactions()?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_rev() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let writes = [
+            ("a", "1"),
+            ("b", "1"),
+            ("c", "1"),
+            ("a", "2"),
+            ("c", "2"),
+            ("a", "3"),
+        ];
+        for (key, value) in writes {
+            let mut txn = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+            txn.set(key.as_bytes(), value.as_bytes().to_vec())?;
+            txn.commit()?;
+        }
+        let mut txn = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+        txn.delete(&Bytes::from("b"))?;
+        txn.set(b"d", b"1".to_vec())?;
+        txn.commit()?;
+        // Not visible to the reader below.
+        let mut uncommitted = Transaction::begin(mvcc.stroe.clone(), Mode::ReadWrite)?;
+        uncommitted.set(b"e", b"1".to_vec())?;
+        uncommitted.set(b"c", b"3".to_vec())?;
+
+        let txn = Transaction::begin(mvcc.stroe.clone(), Mode::ReadOnly)?;
+        let entry = |k: &str, v: &str| (k.as_bytes().to_vec(), v.as_bytes().to_vec());
+        assert_eq!(
+            txn.scan(..)?.rev().collect::<Result<Vec<_>>>()?,
+            vec![entry("d", "1"), entry("c", "2"), entry("a", "3")]
+        );
+
+        // Consuming from both ends yields each key once.
+        let mut scan = txn.scan(..)?;
+        assert_eq!(scan.next().transpose()?, Some(entry("a", "3")));
+        assert_eq!(scan.next_back().transpose()?, Some(entry("d", "1")));
+        assert_eq!(scan.next_back().transpose()?, Some(entry("c", "2")));
+        assert_eq!(scan.next().transpose()?, None);
+        assert_eq!(scan.next_back().transpose()?, None);
+
+        let mut scan = txn.scan(..)?;
+        assert_eq!(scan.next_back().transpose()?, Some(entry("d", "1")));
+        assert_eq!(scan.next_back().transpose()?, Some(entry("c", "2")));
+        assert_eq!(scan.next().transpose()?, Some(entry("a", "3")));
+        assert_eq!(scan.next().transpose()?, None);
+        uncommitted.rollback()?;
+        Ok(())
+    }
+
+    /// A store that persists a dump of its contents on flush, standing in for a file.
+    struct DumpingStore {
+        inner: Memory,
+        disk: Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+
+    impl DumpingStore {
+        /// Reopens the store from what was last flushed.
+        fn open(disk: Arc<std::sync::Mutex<Vec<u8>>>) -> Result<Self> {
+            let mut inner = Memory::new();
+            inner.load(&mut disk.lock().unwrap().as_slice())?;
+            Ok(Self { inner, disk })
+        }
+    }
+
+    impl Store for DumpingStore {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            self.inner.get(key)
+        }
+
+        fn scan(&self, range: Range) -> Scan {
+            self.inner.scan(range)
+        }
+
+        fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+            self.inner.set(key, value)
+        }
+
+        fn delete(&mut self, key: &[u8]) -> Result<()> {
+            self.inner.delete(key)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            let mut disk = self.disk.lock().unwrap();
+            disk.clear();
+            self.inner.dump(&mut *disk)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_commit_no_flush() -> Result<()> {
+        let disk = Arc::default();
+        let mvcc = MVCC::new(Box::new(DumpingStore::open(Arc::clone(&disk))?)).with_sync(false);
+        for i in 0..5u8 {
+            let mut txn = mvcc.begin(Mode::ReadWrite)?;
+            txn.set(&[i], vec![i])?;
+            txn.commit()?;
+        }
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        txn.set(&[5], vec![5])?;
+        txn.commit_no_flush()?;
+
+        // Nothing has been flushed, so reopening loses the commits.
+        let reopened = MVCC::new(Box::new(DumpingStore::open(Arc::clone(&disk))?));
+        assert_eq!(reopened.begin(Mode::ReadOnly)?.scan(..)?.count(), 0);
+
+        mvcc.flush()?;
+        let reopened = MVCC::new(Box::new(DumpingStore::open(Arc::clone(&disk))?));
+        let txn = reopened.begin(Mode::ReadOnly)?;
+        let expect = (0..6u8).map(|i| (vec![i], vec![i])).collect::<Vec<_>>();
+        assert_eq!(txn.scan(..)?.collect::<Result<Vec<_>>>()?, expect);
+        txn.commit()?;
+
+        // With sync, every commit is durable.
+        let mvcc = MVCC::new(Box::new(DumpingStore::open(Arc::clone(&disk))?));
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        txn.set(&[6], vec![6])?;
+        txn.commit()?;
+        let reopened = MVCC::new(Box::new(DumpingStore::open(disk)?));
+        assert_eq!(
+            reopened.begin(Mode::ReadOnly)?.get(&Bytes::from(vec![6]))?,
+            Some(vec![6])
+        );
+        Ok(())
+    }
+
+    /// Records the fields of every event as strings, keyed by field name.
+    #[cfg(feature = "tracing")]
+    struct CaptureEvents(Arc<std::sync::Mutex<Vec<std::collections::HashMap<String, String>>>>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for CaptureEvents {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            struct Visitor(std::collections::HashMap<String, String>);
+            impl tracing::field::Visit for Visitor {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    self.0
+                        .insert(field.name().to_string(), format!("{:?}", value));
+                }
+            }
+            let mut visitor = Visitor(Default::default());
+            event.record(&mut visitor);
+            self.0.lock().unwrap().push(visitor.0);
+        }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() -> Result<()> {
+        let events = Arc::default();
+        let subscriber = CaptureEvents(Arc::clone(&events));
+        let id = tracing::subscriber::with_default(subscriber, || -> Result<u64> {
+            let mvcc = MVCC::new(Box::new(Memory::new()));
+            let mut other = mvcc.begin(Mode::ReadWrite)?;
+            other.set(b"key", b"other".to_vec())?;
+            let mut txn = mvcc.begin(Mode::ReadWrite)?;
+            assert!(txn.set(b"key", b"value".to_vec()).is_err());
+            txn.set(b"other", b"value".to_vec())?;
+            let id = txn.id();
+            txn.commit()?;
+            other.rollback()?;
+            Ok(id)
+        })?;
+
+        let events = events.lock().unwrap();
+        let find = |message: &str, key_len: Option<&str>| {
+            events.iter().find(|event| {
+                event["message"] == message
+                    && event["txn_id"] == id.to_string()
+                    && key_len.map_or(true, |len| event["key_len"] == len)
+            })
+        };
+        assert!(find("begin", None).is_some());
+        assert_eq!(find("write", Some("3")).unwrap()["conflict"], "true");
+        assert_eq!(find("write", Some("5")).unwrap()["conflict"], "false");
+        assert!(find("commit", None).is_some());
+        assert!(find("rollback", None).is_none());
+        // Memory events carry sizes, never the data itself.
+        let set = events
+            .iter()
+            .find(|event| event["message"] == "set")
+            .unwrap();
+        assert!(set.contains_key("key_len") && set.contains_key("value_len"));
+        assert!(!events
+            .iter()
+            .flat_map(|event| event.values())
+            .any(|v| v.contains("value")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_key() -> Result<()> {
+        let key = RecordKey::new(b"key".to_vec(), 7);
+        assert_eq!(
+            key.encode(),
+            b"\xffkey\x00\x00\x00\x00\x00\x00\x00\x07".to_vec()
+        );
+        assert_eq!(RecordKey::decode(&key.encode())?, key);
+        assert_eq!(
+            RecordKey::decode(&RecordKey::new(vec![], 0).encode())?.user_key,
+            vec![]
+        );
+
+        assert!(RecordKey::decode(&[]).is_err());
+        assert!(RecordKey::decode(&[0xff, 0x01]).is_err());
+        assert!(RecordKey::decode(&Key::TxnActive(1).encode()).is_err());
+        assert!(RecordKey::decode(&Key::Metadata(Cow::from(&b"0123456789"[..])).encode()).is_err());
+
+        // Record keys written by transactions decode to the user key and the writer's id.
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        txn.set(b"a", b"1".to_vec())?;
+        let id = txn.id();
+        txn.commit()?;
+        let store = mvcc.stroe.read().unwrap();
+        let records = store
+            .scan(Range::from(vec![0xff]..))
+            .map(|item| RecordKey::decode(&item?.0))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(records, vec![RecordKey::new(b"a".to_vec(), id)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_key_round_trip() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..32);
+            let user_key: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let key = RecordKey::new(user_key, rng.gen());
+            assert_eq!(RecordKey::decode(&key.encode())?, key);
+
+            let older = RecordKey::new(key.user_key.clone(), rng.gen_range(0..=key.version));
+            assert!(older.encode() <= key.encode());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_transact() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new())).with_max_retries(2);
+        let attempts = Cell::new(0);
+        let value = mvcc.transact(|txn| {
+            attempts.set(attempts.get() + 1);
+            // Conflict with a concurrent writer on the first attempt only.
+            let mut other = mvcc.begin(Mode::ReadWrite)?;
+            if attempts.get() == 1 {
+                other.set(b"a", b"other".to_vec())?;
+            }
+            let result = txn.set(b"a", b"1".to_vec());
+            other.rollback()?;
+            result?;
+            Ok(attempts.get())
+        })?;
+        assert_eq!(value, 2);
+        assert_eq!(
+            mvcc.begin(Mode::ReadOnly)?.get(&Bytes::from("a"))?,
+            Some(b"1".to_vec())
+        );
+
+        // Retries are limited, and the last conflict is returned.
+        attempts.set(0);
+        let err = mvcc
+            .transact(|txn| -> Result<()> {
+                attempts.set(attempts.get() + 1);
+                txn.set(b"b", b"1".to_vec())?;
+                Err(Error::Conflict.into())
+            })
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::Conflict));
+        assert_eq!(attempts.get(), 3);
+
+        // Other errors aren't retried, and roll back.
+        attempts.set(0);
+        let err = mvcc
+            .transact(|txn| -> Result<()> {
+                attempts.set(attempts.get() + 1);
+                txn.set(b"b", b"1".to_vec())?;
+                Err(anyhow!("failed"))
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "failed");
+        assert_eq!(attempts.get(), 1);
+        assert_eq!(mvcc.begin(Mode::ReadOnly)?.get(&Bytes::from("b"))?, None);
+        assert!(mvcc.active_transactions()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        assert_eq!(retry_backoff(0), RETRY_BACKOFF);
+        assert_eq!(retry_backoff(3), RETRY_BACKOFF * 8);
+        // Many retries neither overflow nor wait for longer than the cap.
+        assert_eq!(retry_backoff(10), MAX_RETRY_BACKOFF);
+        assert_eq!(retry_backoff(33), MAX_RETRY_BACKOFF);
+        assert_eq!(retry_backoff(u32::MAX), MAX_RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn test_begin_snapshot() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        txn.set(b"a", b"1".to_vec())?;
+        txn.commit()?;
+        let mut active = mvcc.begin(Mode::ReadWrite)?;
+        active.set(b"b", b"1".to_vec())?;
+
+        let snapshot = mvcc.begin_snapshot_now()?;
+        assert_eq!(
+            snapshot.mode(),
+            Mode::Snapshot {
+                version: snapshot.id()
+            }
+        );
+        assert_eq!(snapshot.get(&Bytes::from("a"))?, Some(b"1".to_vec()));
+
+        // Neither later commits nor transactions active at the snapshot are visible.
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        txn.set(b"a", b"2".to_vec())?;
+        txn.commit()?;
+        active.commit()?;
+        assert_eq!(snapshot.get(&Bytes::from("a"))?, Some(b"1".to_vec()));
+        assert_eq!(snapshot.get(&Bytes::from("b"))?, None);
+        let scan = snapshot.scan(..)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(scan, vec![(b"a".to_vec(), b"1".to_vec())]);
+
+        let mut pinned = mvcc.begin_snapshot_at(snapshot.id())?;
+        assert_eq!(pinned.get(&Bytes::from("a"))?, Some(b"1".to_vec()));
+        assert!(pinned.set(b"a", b"3".to_vec()).is_err());
+        assert_eq!(
+            mvcc.begin(Mode::ReadOnly)?.get(&Bytes::from("a"))?,
+            Some(b"2".to_vec())
+        );
+        snapshot.commit()?;
+        pinned.commit()?;
+
+        // A missing snapshot errors without leaving the transaction active.
+        assert!(mvcc.begin_snapshot_at(1000).is_err());
+        assert!(mvcc.active_transactions()?.is_empty());
+
+        // Reads skip records of longer keys, whose encoding falls within the key's versions.
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        txn.set(b"a\x00", b"other".to_vec())?;
+        assert_eq!(txn.get(&Bytes::from("a"))?, Some(b"2".to_vec()));
+        txn.delete(&Bytes::from("a"))?;
+        assert_eq!(txn.get(&Bytes::from("a"))?, None);
+        Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_delete_existing() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        txn.set(b"a", b"1".to_vec())?;
+        txn.commit()?;
+
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        txn.delete_existing(b"a")?;
+        assert_eq!(txn.get(&Bytes::from("a"))?, None);
+        let err = txn.delete_existing(b"a").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::NotFound(b"a".to_vec()))
+        );
+        let err = txn.delete_existing(b"b").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::NotFound(b"b".to_vec()))
+        );
+
+        // Keys written by the transaction itself can be deleted.
+        txn.set(b"b", b"1".to_vec())?;
+        txn.delete_existing(b"b")?;
+        txn.commit()?;
+        assert_eq!(mvcc.begin(Mode::ReadOnly)?.scan(..)?.count(), 0);
+
+        // Keys committed after the transaction's snapshot are not visible to it.
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        let mut other = mvcc.begin(Mode::ReadWrite)?;
+        other.set(b"c", b"1".to_vec())?;
+        other.commit()?;
+        assert!(txn.delete_existing(b"c").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_group_scan() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let mut ids = Vec::new();
+        for round in 0..2 {
+            let mut txn = mvcc.begin(Mode::ReadWrite)?;
+            ids.push(txn.id());
+            for key in [&b"a"[..], b"b", b"c"] {
+                match (round, key) {
+                    (1, b"b") => txn.delete(&Bytes::from("b"))?,
+                    _ => txn.set(key, vec![round])?,
+                }
+            }
+            txn.commit()?;
+        }
+
+        let session = mvcc.stroe.read().unwrap();
+        let scan = session.scan(Range::from(Key::Record(vec![].into(), 0).encode()..));
+        let groups = VersionGroupScan::new(scan).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            groups,
+            vec![
+                (b"a".to_vec(), vec![(ids[0], false), (ids[1], false)]),
+                (b"b".to_vec(), vec![(ids[0], false), (ids[1], true)]),
+                (b"c".to_vec(), vec![(ids[0], false), (ids[1], false)]),
+            ]
+        );
+
+        let scan = session.scan(Range::from(Key::Record(vec![].into(), 0).encode()..));
+        assert_eq!(
+            VersionGroupScan::new(scan).last().transpose()?,
+            Some(groups[2].clone())
+        );
+        let scan = session.scan(Range::from(vec![0xff]..vec![0xff]));
+        assert_eq!(VersionGroupScan::new(scan).count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_store_downcast() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        txn.set(b"a", b"1".to_vec())?;
+        txn.commit()?;
+
+        let usage = mvcc.with_store(|store| {
+            store
+                .as_any()
+                .downcast_ref::<Memory>()
+                .map(|memory| memory.memory_usage())
+        });
+        assert!(usage.unwrap().data_bytes > 0);
+        mvcc.with_store(|store| {
+            let memory = store.as_any().downcast_ref::<Memory>().unwrap();
+            memory.verify()
+        })?;
+
+        // Stores that don't support downcasting can't be downcast to anything.
+        let mvcc = MVCC::new(Box::new(PrefixedStore::new(Memory::new(), b"p".to_vec())));
+        assert!(mvcc.with_store(|store| store.as_any().downcast_ref::<Memory>().is_none()));
+        assert!(mvcc.with_store(|store| {
+            store
+                .as_any()
+                .downcast_ref::<PrefixedStore<Memory>>()
+                .is_none()
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_keys_and_count() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        for key in [&b"a"[..], b"b", b"c", b"d", b"e"] {
+            txn.set(key, b"1".to_vec())?;
+        }
+        txn.commit()?;
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        txn.set(b"a", b"2".to_vec())?;
+        txn.delete(&Bytes::from("b"))?;
+        txn.set(b"c", b"2".to_vec())?;
+        txn.delete(&Bytes::from("c"))?;
+        txn.commit()?;
+        // A deleted key that is set again, and an uncommitted write that isn't visible.
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        txn.set(b"b", b"3".to_vec())?;
+        txn.commit()?;
+        let mut active = mvcc.begin(Mode::ReadWrite)?;
+        active.set(b"f", b"1".to_vec())?;
+
+        let txn = mvcc.begin(Mode::ReadOnly)?;
+        let keys = txn.scan_keys(..)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            keys,
+            vec![b"a".to_vec(), b"b".to_vec(), b"d".to_vec(), b"e".to_vec()]
+        );
+        let rev = txn.scan_keys(..)?.rev().collect::<Result<Vec<_>>>()?;
+        assert_eq!(rev, keys.iter().rev().cloned().collect::<Vec<_>>());
+        let scanned = txn.scan(..)?.map(|item| item.map(|(key, _)| key));
+        assert_eq!(keys, scanned.collect::<Result<Vec<_>>>()?);
+
+        assert_eq!(txn.count(..)?, 4);
+        assert_eq!(txn.count(b"b".to_vec()..=b"d".to_vec())?, 2);
+        assert_eq!(txn.count(b"c".to_vec()..b"d".to_vec())?, 0);
+        assert_eq!(active.count(..)?, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_changes_since() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(Memory::new()));
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        txn.set(b"a", b"1".to_vec())?;
+        txn.set(b"b", b"1".to_vec())?;
+        txn.commit()?;
+        let mut txn = mvcc.begin(Mode::ReadWrite)?;
+        let mark = txn.id();
+        txn.set(b"a", b"2".to_vec())?;
+        txn.commit()?;
+
+        let mut second = mvcc.begin(Mode::ReadWrite)?;
+        let mut third = mvcc.begin(Mode::ReadWrite)?;
+        third.set(b"a", b"3".to_vec())?;
+        third.delete(&Bytes::from("b"))?;
+        third.commit()?;
+        // Uncommitted and rolled back writes aren't changes.
+        second.set(b"c", b"2".to_vec())?;
+        let mut rolled_back = mvcc.begin(Mode::ReadWrite)?;
+        rolled_back.set(b"d", b"4".to_vec())?;
+        rolled_back.rollback()?;
+
+        assert_eq!(
+            mvcc.changes_since(mark)?,
+            vec![
+                (b"a".to_vec(), mark + 2, Some(b"3".to_vec())),
+                (b"b".to_vec(), mark + 2, None)
+            ]
+        );
+        second.commit()?;
+        let changes = mvcc.changes_since(mark)?;
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0], (b"c".to_vec(), mark + 1, Some(b"2".to_vec())));
+        assert_eq!(mvcc.changes_since(mark - 1)?.len(), 4);
+        assert!(mvcc.changes_since(mark + 2)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use std::ops::Bound;
+
+use anyhow::Result;
+
+use super::{Keys, Range, Scan, Store};
+
+/// A store wrapper that namespaces all keys under a prefix, so that several logical stores can
+/// share one physical store. Keys are yielded with the prefix stripped.
+pub struct PrefixedStore<S: Store> {
+    inner: S,
+    prefix: Vec<u8>,
+}
+
+impl<S: Store> PrefixedStore<S> {
+    pub fn new(inner: S, prefix: Vec<u8>) -> Self {
+        Self { inner, prefix }
+    }
+
+    fn prefixed(&self, key: &[u8]) -> Vec<u8> {
+        let mut prefixed = Vec::with_capacity(self.prefix.len() + key.len());
+        prefixed.extend_from_slice(&self.prefix);
+        prefixed.extend_from_slice(key);
+        prefixed
+    }
+
+    /// Translates a range over unprefixed keys into the range it covers in the inner store.
+    fn prefixed_range(&self, range: Range) -> Range {
+        let start = match range.start {
+            Bound::Included(k) => Bound::Included(self.prefixed(&k)),
+            Bound::Excluded(k) => Bound::Excluded(self.prefixed(&k)),
+            Bound::Unbounded => Bound::Included(self.prefix.clone()),
+        };
+        let end = match range.end {
+            Bound::Included(k) => Bound::Included(self.prefixed(&k)),
+            Bound::Excluded(k) => Bound::Excluded(self.prefixed(&k)),
+            Bound::Unbounded => match prefix_end(&self.prefix) {
+                Some(end) => Bound::Excluded(end),
+                None => Bound::Unbounded,
+            },
+        };
+        Range { start, end }
+    }
+}
+
+/// Returns the first key after all keys starting with the prefix, or None if there is no such
+/// key because the prefix is empty or all 0xff.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let i = prefix.iter().rposition(|b| *b != 0xff)?;
+    let mut end = prefix[..=i].to_vec();
+    end[i] += 1;
+    Some(end)
+}
+
+impl<S: Store> Store for PrefixedStore<S> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get(&self.prefixed(key))
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.inner.contains_key(&self.prefixed(key))
+    }
+
+    fn scan(&self, range: Range) -> Scan {
+        let len = self.prefix.len();
+        Box::new(
+            self.inner
+                .scan(self.prefixed_range(range))
+                .map(move |item| item.map(|(key, value)| (key[len..].to_vec(), value))),
+        )
+    }
+
+    fn scan_keys(&self, range: Range) -> Keys {
+        let len = self.prefix.len();
+        Box::new(
+            self.inner
+                .scan_keys(self.prefixed_range(range))
+                .map(move |item| item.map(|key| key[len..].to_vec())),
+        )
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let key = self.prefixed(key);
+        self.inner.set(&key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let key = self.prefixed(key);
+        self.inner.delete(&key)
+    }
+
+    fn range_delete(&mut self, range: Range) -> Result<u64> {
+        let range = self.prefixed_range(range);
+        self.inner.range_delete(range)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn close(self: Box<Self>) -> Result<()> {
+        Box::new(self.inner).close()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::memory::Memory;
+    use super::*;
+
+    fn collect(scan: Scan) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        scan.collect()
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"a/"), Some(b"a0".to_vec()));
+        assert_eq!(prefix_end(&[1, 0xff]), Some(vec![2]));
+        assert_eq!(prefix_end(&[0xff, 0xff]), None);
+        assert_eq!(prefix_end(&[]), None);
+    }
+
+    #[test]
+    fn test_isolation() -> Result<()> {
+        let mut mem = Memory::new();
+        mem.set(b"a.", b"outside")?;
+        mem.set(b"a0", b"outside")?;
+        let mut a = PrefixedStore::new(mem.share(), b"a/".to_vec());
+        let mut b = PrefixedStore::new(mem.share(), b"b/".to_vec());
+
+        a.set(b"k1", b"a1")?;
+        a.set(b"k2", b"a2")?;
+        b.set(b"k1", b"b1")?;
+        assert_eq!(a.get(b"k1")?, Some(b"a1".to_vec()));
+        assert_eq!(b.get(b"k1")?, Some(b"b1".to_vec()));
+        assert_eq!(b.get(b"k2")?, None);
+        assert_eq!(mem.get(b"a/k1")?, Some(b"a1".to_vec()));
+
+        assert_eq!(
+            collect(a.scan(Range::from(..)))?,
+            vec![
+                (b"k1".to_vec(), b"a1".to_vec()),
+                (b"k2".to_vec(), b"a2".to_vec())
+            ]
+        );
+        assert_eq!(
+            collect(a.scan(Range::from(b"k2".to_vec()..)))?,
+            vec![(b"k2".to_vec(), b"a2".to_vec())]
+        );
+        assert_eq!(
+            collect(a.scan(Range::from(..b"k2".to_vec())))?,
+            vec![(b"k1".to_vec(), b"a1".to_vec())]
+        );
+        assert_eq!(
+            collect(b.scan(Range::from(..)))?,
+            vec![(b"k1".to_vec(), b"b1".to_vec())]
+        );
+
+        b.delete(b"k1")?;
+        assert_eq!(b.get(b"k1")?, None);
+        assert_eq!(a.get(b"k1")?, Some(b"a1".to_vec()));
+        Ok(())
+    }
+}
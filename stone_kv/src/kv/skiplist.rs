@@ -1,11 +1,14 @@
 use rand::random;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
 use std::{cmp, mem, ptr};
 
 use super::arena::*;
 use super::comparator::*;
-use super::{BRANCHING, MAX_HEIGHT};
+use super::lock::{Lock, LockKind};
+use super::{Range, BRANCHING, MAX_HEIGHT};
 
 #[derive(Debug)]
 #[repr(C)]
@@ -18,9 +21,14 @@ pub struct Node {
 }
 
 impl Node {
-    fn new<A: Arena>(arena: &A, key: Vec<u8>, value: Vec<u8>, height: usize) -> *const Self {
+    /// Returns the number of arena bytes taken by a node of the given height.
+    fn allocation_size(height: usize) -> usize {
         let pointers_size = (height + 1) * mem::size_of::<Self>();
-        let size = mem::size_of::<Self>() + pointers_size;
+        mem::size_of::<Self>() + pointers_size
+    }
+
+    fn new<A: Arena>(arena: &A, key: Vec<u8>, value: Vec<u8>, height: usize) -> *const Self {
+        let size = Self::allocation_size(height);
         let align = mem::align_of::<Self>();
         let p = unsafe { arena.allocate(size, align) } as *const Self as *mut Self;
         unsafe {
@@ -40,9 +48,17 @@ impl Node {
         let value = self.get_value().to_owned();
         (key, value)
     }
+
+    /// Replaces the value. A value of the same length is copied into the existing buffer
+    /// rather than reallocated, so the caller must hold the write lock, and readers must copy
+    /// values under the read lock (see `Skiplist::read_node`) to not see one half written.
     #[inline]
-    pub fn set_value(&mut self, value: Vec<u8>) {
-        self.value = value;
+    fn set_value(&mut self, value: &[u8]) {
+        if value.len() == self.value.len() {
+            self.value.copy_from_slice(value);
+        } else {
+            self.value = value.to_owned();
+        }
     }
 
     #[inline]
@@ -85,8 +101,14 @@ impl Node {
     }
 }
 
+/// The number of nodes `estimate_count` aims to sample.
+const ESTIMATE_SAMPLE: usize = 256;
+
+/// A skiplist ordered by a comparator, with its nodes allocated from an arena. Reads take the
+/// lock shared and writes take it exclusively, finding where to write while they hold it, so
+/// any number of threads can read and write at once.
 pub struct Skiplist<C: Comparator, A: Arena> {
-    inner: Arc<RwLock<Inner<C, A>>>,
+    inner: Arc<Lock<Inner<C, A>>>,
 }
 
 unsafe impl<C: Comparator, A: Arena> Send for Skiplist<C, A> {}
@@ -108,10 +130,117 @@ struct Inner<C: Comparator, A: Arena> {
     comparator: C,
     count: usize,
     size: usize,
+    /// Bytes of the keys and values of linked nodes.
+    data_size: usize,
+    /// Arena bytes taken by the linked nodes and the sentinels.
+    node_size: usize,
+}
+
+impl<C: Comparator, A: Arena> Inner<C, A> {
+    /// Returns the highest level with at least `ESTIMATE_SAMPLE` nodes, or the first level if
+    /// there's none. The caller must hold the lock.
+    fn sample_level(&self) -> usize {
+        let tail = self.tail as *mut Node;
+        let level_len = |level: usize| unsafe {
+            let mut len = 0;
+            let mut node = (*self.head).get_next(level);
+            while node != tail && len < ESTIMATE_SAMPLE {
+                len += 1;
+                node = (*node).get_next(level);
+            }
+            len
+        };
+        (1..=self.max_height)
+            .rev()
+            .find(|&level| level_len(level) >= ESTIMATE_SAMPLE)
+            .unwrap_or(1)
+    }
+
+    /// Finds the first node with a key greater than or equal to the given key, recording the
+    /// last node before it at each level in `prev`. The caller must hold the lock.
+    fn seek(&self, key: &[u8], prev: &mut [*const Node]) -> *const Node {
+        let mut node = self.head;
+        unsafe {
+            for level in (1..=self.max_height).rev() {
+                let mut next = (*node).get_next(level);
+                while !std::ptr::eq(next, self.tail)
+                    && self.comparator.compare((*next).get_key(), key) == cmp::Ordering::Less
+                {
+                    node = next;
+                    next = (*node).get_next(level);
+                }
+                prev[level - 1] = node;
+            }
+            (*node).get_next(1)
+        }
+    }
+
+    /// Finds the first node within a start bound, which is the tail if there's none. The caller
+    /// must hold the lock.
+    fn seek_start(&self, start: &Bound<Vec<u8>>) -> *const Node {
+        let mut prev = [ptr::null(); MAX_HEIGHT];
+        match start {
+            Bound::Included(k) => self.seek(k, &mut prev),
+            Bound::Excluded(k) => unsafe {
+                let node = self.seek(k, &mut prev);
+                match !std::ptr::eq(node, self.tail)
+                    && self.comparator.compare((*node).get_key(), k) == cmp::Ordering::Equal
+                {
+                    true => (*node).get_next(1),
+                    false => node,
+                }
+            },
+            Bound::Unbounded => unsafe { (*self.head).get_next(1) },
+        }
+    }
+
+    /// Returns true if the key sorts at or before the node. The head sorts before every key
+    /// and the tail after.
+    fn key_is_less_than_or_equal(&self, key: &[u8], n: *const Node) -> bool {
+        if std::ptr::eq(n, self.head) {
+            false
+        } else if std::ptr::eq(n, self.tail) {
+            true
+        } else {
+            let node_key = unsafe { (*n).get_key() };
+            self.comparator.compare(key, node_key) != cmp::Ordering::Greater
+        }
+    }
+
+    /// Links a new node in after the nodes in `prev`, as found by a search for its key. The
+    /// caller must hold the write lock.
+    fn link(&mut self, key: Vec<u8>, value: Vec<u8>, prev: &mut [*const Node]) {
+        let height = rand_height();
+        if height > self.max_height {
+            for p in prev.iter_mut().take(height).skip(self.max_height) {
+                *p = self.head;
+            }
+            self.max_height = height;
+        }
+        self.data_size += key.len() + value.len();
+        self.node_size += Node::allocation_size(height);
+        let new_node = Node::new(&self.arena, key, value, height) as *mut Node;
+        unsafe {
+            let tmp = (*(prev[0] as *mut Node)).get_next_at_first_level();
+            (*tmp).set_prev(new_node);
+            (*new_node).set_prev(prev[0] as *mut Node);
+            for i in 1..=height {
+                (*new_node).set_next(i, (*(prev[i - 1])).get_next(i));
+                (*(prev[i - 1] as *mut Node)).set_next(i, new_node);
+            }
+        }
+        self.count += 1;
+        self.size += 1;
+    }
 }
 
 impl<C: Comparator, A: Arena> Skiplist<C, A> {
     pub fn new(cmp: C, arena: A) -> Self {
+        Self::new_with_lock(cmp, arena, LockKind::default())
+    }
+
+    /// Creates a skiplist guarded by the given kind of lock. See `LockKind` for the tradeoffs.
+    pub fn new_with_lock(cmp: C, arena: A, lock: LockKind) -> Self {
         let head = Node::new(&arena, Vec::new(), Vec::new(), MAX_HEIGHT) as *mut Node;
         let tail = Node::new(&arena, Vec::new(), Vec::new(), MAX_HEIGHT) as *mut Node;
 
@@ -130,28 +259,98 @@ impl<C: Comparator, A: Arena> Skiplist<C, A> {
             comparator: cmp,
             count: 0,
             size: 0,
+            data_size: 0,
+            node_size: 2 * Node::allocation_size(MAX_HEIGHT),
         };
         Self {
-            inner: Arc::new(RwLock::new(inner)),
+            inner: Arc::new(Lock::new(lock, inner)),
         }
     }
 
+    /// Builds a skiplist from entries already sorted by key, appending each at the end in O(1)
+    /// by keeping the last node on every level, rather than searching from the top for each.
+    /// The order is only checked in debug builds.
+    pub fn from_sorted(cmp: C, arena: A, iter: impl Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Self {
+        let skiplist = Self::new(cmp, arena);
+        {
+            let mut inner = skiplist.inner.write();
+            let tail = inner.tail as *mut Node;
+            let mut last = [inner.head as *mut Node; MAX_HEIGHT];
+            for (key, value) in iter {
+                debug_assert!(
+                    std::ptr::eq(last[0], inner.head)
+                        || inner
+                            .comparator
+                            .compare(unsafe { (*last[0]).get_key() }, &key)
+                            == cmp::Ordering::Less,
+                    "[skiplist] from_sorted input is not sorted at key {:?}",
+                    key
+                );
+                let height = rand_height();
+                inner.max_height = inner.max_height.max(height);
+                inner.data_size += key.len() + value.len();
+                inner.node_size += Node::allocation_size(height);
+                let node = Node::new(&inner.arena, key, value, height) as *mut Node;
+                unsafe {
+                    (*node).set_prev(last[0]);
+                    (*tail).set_prev(node);
+                    for level in 1..=height {
+                        (*last[level - 1]).set_next(level, node);
+                        (*node).set_next(level, tail);
+                        last[level - 1] = node;
+                    }
+                }
+                inner.count += 1;
+                inner.size += 1;
+            }
+        }
+        skiplist
+    }
+
     #[inline]
     pub fn count(&self) -> usize {
-        let inner = self.inner.read().unwrap();
+        let inner = self.inner.read();
         inner.count
     }
 
+    /// Returns the number of lock acquisitions that had to wait for another thread.
+    pub fn lock_contention_count(&self) -> u64 {
+        self.inner.contention_count()
+    }
+
     #[inline]
     pub fn total_size(&self) -> usize {
-        let inner = self.inner.read().unwrap();
+        let inner = self.inner.read();
         inner.size
     }
 
+    /// Returns the bytes of all keys and values. They're owned by the nodes rather than
+    /// allocated from the arena.
+    pub fn data_size(&self) -> usize {
+        let inner = self.inner.read();
+        inner.data_size
+    }
+
+    /// Returns the arena bytes taken by the nodes themselves, i.e. their headers and pointer
+    /// towers, including the head and tail sentinels.
+    pub fn node_size(&self) -> usize {
+        let inner = self.inner.read();
+        inner.node_size
+    }
+
+    /// Returns the bytes the arena has allocated. Unlinked nodes aren't returned to it, so this
+    /// never shrinks.
+    pub fn memory_used(&self) -> usize {
+        let inner = self.inner.read();
+        inner.arena.memory_used()
+    }
+
+    /// Returns the node for a key, or null if the key isn't present. The node is owned by the
+    /// skiplist and lives as long as it does.
     pub fn get(&self, key: &[u8]) -> *mut Node {
         let node = self.get_greater_or_equal(key);
-        let inner = self.inner.read().unwrap();
-        if !self.is_tail(node) {
+        let inner = self.inner.read();
+        if !std::ptr::eq(node, inner.tail) {
             unsafe {
                 if inner.comparator.compare((*node).get_key(), key) == cmp::Ordering::Equal {
                     return node as *mut _;
@@ -161,17 +360,73 @@ impl<C: Comparator, A: Arena> Skiplist<C, A> {
         ptr::null_mut()
     }
 
+    /// Calls `f` with a node under the read lock. Values are overwritten in place under the
+    /// write lock, so reading a node's value without holding the lock can see it half written.
+    ///
+    /// # Safety
+    ///
+    /// The node must have been linked into this list, and must not be the head or tail.
+    pub unsafe fn read_node<R>(&self, node: *const Node, f: impl FnOnce(&Node) -> R) -> R {
+        let _inner = self.inner.read();
+        f(&*node)
+    }
+
+    /// Returns a copy of the key and value for a key, or None if the key isn't present. Unlike
+    /// `get`, callers don't have to check and dereference a node pointer. The entry is copied
+    /// under the same read lock as the search, since a concurrent insert may replace the value.
+    pub fn get_entry(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        let inner = self.inner.read();
+        let mut prev = [ptr::null(); MAX_HEIGHT];
+        let node = inner.seek(key, &mut prev);
+        unsafe {
+            match !std::ptr::eq(node, inner.tail)
+                && inner.comparator.compare((*node).get_key(), key) == cmp::Ordering::Equal
+            {
+                true => Some((*node).get_key_value()),
+                false => None,
+            }
+        }
+    }
+
+    /// Looks up several keys under a single read lock, returning a copy of the value for each
+    /// key in the same order, or None for keys that are not found. The values are copied while
+    /// the lock is held, since a concurrent insert may replace them.
+    pub fn get_many(&self, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        let inner = self.inner.read();
+        let tail = inner.tail as *mut Node;
+        let compare = |node: *const Node, key: &[u8]| unsafe {
+            inner.comparator.compare((*node).get_key(), key)
+        };
+        keys.iter()
+            .map(|key| unsafe {
+                let mut node = inner.head;
+                for level in (1..=inner.max_height).rev() {
+                    let mut next = (*node).get_next(level);
+                    while next != tail && compare(next, key) == cmp::Ordering::Less {
+                        node = next;
+                        next = (*node).get_next(level);
+                    }
+                }
+                let next = (*node).get_next(1);
+                match next != tail && compare(next, key) == cmp::Ordering::Equal {
+                    true => Some((*next).get_value().to_owned()),
+                    false => None,
+                }
+            })
+            .collect()
+    }
+
     pub fn get_first_greater(&self, key: &[u8]) -> *const Node {
         let node = self.get_greater_or_equal(key);
-        let inner = self.inner.read().unwrap();
-        if !self.is_tail(node) {
+        let inner = self.inner.read();
+        if !std::ptr::eq(node, inner.tail) {
             unsafe {
                 if inner.comparator.compare((*node).get_key(), key) == cmp::Ordering::Greater {
                     return node;
                 }
                 if inner.comparator.compare((*node).get_key(), key) == cmp::Ordering::Equal {
                     let next = (*node).get_next_at_first_level();
-                    return match self.is_tail(next) {
+                    return match std::ptr::eq(next, inner.tail) {
                         true => ptr::null(),
                         false => next,
                     };
@@ -188,15 +443,15 @@ impl<C: Comparator, A: Arena> Skiplist<C, A> {
 
     pub fn get_first_less(&self, key: &[u8]) -> *const Node {
         let node = self.get_less_or_equal(key);
-        let inner = self.inner.read().unwrap();
-        if !self.is_head(node) {
+        let inner = self.inner.read();
+        if !std::ptr::eq(node, inner.head) {
             unsafe {
                 if inner.comparator.compare((*node).get_key(), key) == cmp::Ordering::Less {
                     return node;
                 }
                 if inner.comparator.compare((*node).get_key(), key) == cmp::Ordering::Equal {
                     let next = (*node).get_prev();
-                    return match self.is_head(next as *const _) {
+                    return match std::ptr::eq(next, inner.head) {
                         true => ptr::null(),
                         false => next,
                     };
@@ -206,81 +461,352 @@ impl<C: Comparator, A: Arena> Skiplist<C, A> {
         ptr::null()
     }
 
+    /// Inserts a key, or replaces its value if it's already present. The key and value are
+    /// copied into the node.
     pub fn insert(&self, key: &[u8], value: &[u8]) {
+        let mut inner = self.inner.write();
         let mut prev = [ptr::null(); MAX_HEIGHT];
-        let node = self.find_greater_or_equal(&key, Some(&mut prev));
-        if !node.is_null() {
-            let inner = self.inner.read().unwrap();
+        let node = inner.seek(key, &mut prev) as *mut Node;
+        // The sentinels have empty keys too, so tell them apart by pointer before comparing.
+        if !std::ptr::eq(node, inner.tail) {
             unsafe {
                 if inner.comparator.compare(key, (*node).get_key()) == cmp::Ordering::Equal {
-                    (*(node as *mut Node)).set_value(value.to_owned());
+                    inner.data_size += value.len();
+                    inner.data_size -= (*node).get_value().len();
+                    (*node).set_value(value);
                     return;
                 }
             }
         }
-        let height = rand_height();
-        let new_node = {
-            let mut inner = self.inner.write().unwrap();
-            let max_height = inner.max_height;
-            if height > max_height {
-                for p in prev.iter_mut().take(height).skip(max_height) {
-                    *p = inner.head;
-                }
-                inner.max_height = height;
-            }
-            let new_node =
-                Node::new(&inner.arena, key.to_owned(), value.to_owned(), height) as *mut Node;
+        inner.link(key.to_owned(), value.to_owned(), &mut prev);
+    }
+
+    /// Like insert, but moves the key and value into the node instead of copying them.
+    pub fn insert_owned(&self, key: Vec<u8>, value: Vec<u8>) {
+        let mut inner = self.inner.write();
+        let mut prev = [ptr::null(); MAX_HEIGHT];
+        let node = inner.seek(&key, &mut prev) as *mut Node;
+        if !std::ptr::eq(node, inner.tail) {
             unsafe {
-                let tmp = (*(prev[0] as *mut Node)).get_next_at_first_level();
-                if std::ptr::eq(tmp, inner.tail) {
-                    (*tmp).set_prev(new_node);
+                if inner.comparator.compare(&key, (*node).get_key()) == cmp::Ordering::Equal {
+                    inner.data_size += value.len();
+                    inner.data_size -= (*node).get_value().len();
+                    (*node).value = value;
+                    return;
                 }
             }
-            inner.count += 1;
-            inner.size += 1;
-            new_node
-        };
+        }
+        inner.link(key, value, &mut prev);
+    }
+
+    /// Inserts a key only if it is not present, checking and inserting under a single write
+    /// lock. Returns true if the key was inserted.
+    pub fn insert_if_absent(&self, key: &[u8], value: &[u8]) -> bool {
+        let mut inner = self.inner.write();
+        let mut prev = [ptr::null(); MAX_HEIGHT];
+        let node = inner.seek(key, &mut prev);
+        let exists = !std::ptr::eq(node, inner.tail)
+            && inner.comparator.compare(unsafe { (*node).get_key() }, key) == cmp::Ordering::Equal;
+        if !exists {
+            inner.link(key.to_owned(), value.to_owned(), &mut prev);
+        }
+        !exists
+    }
+
+    /// Returns a copy of the value for a key, first inserting the value from `f` if the key is
+    /// not present. The check and insert happen under a single write lock, so `f` runs at most
+    /// once per key even with concurrent callers. `f` must not access the skiplist.
+    pub fn get_or_insert_with(&self, key: &[u8], f: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        match self.try_get_or_insert_with(key, || Ok::<_, Infallible>(f())) {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
 
+    /// Like `get_or_insert_with`, but `f` can fail, in which case nothing is inserted and its
+    /// error is returned.
+    pub fn try_get_or_insert_with<E>(
+        &self,
+        key: &[u8],
+        f: impl FnOnce() -> Result<Vec<u8>, E>,
+    ) -> Result<Vec<u8>, E> {
+        let mut inner = self.inner.write();
+        let mut prev = [ptr::null(); MAX_HEIGHT];
+        let node = inner.seek(key, &mut prev);
         unsafe {
-            (*new_node).set_prev(prev[0] as *mut Node);
-            for i in 1..=height {
-                (*new_node).set_next(i, (*(prev[i - 1])).get_next(i));
-                (*(prev[i - 1] as *mut Node)).set_next(i, new_node);
+            if !std::ptr::eq(node, inner.tail)
+                && inner.comparator.compare((*node).get_key(), key) == cmp::Ordering::Equal
+            {
+                return Ok((*node).get_value().to_owned());
             }
         }
+        let value = f()?;
+        inner.link(key.to_owned(), value.clone(), &mut prev);
+        Ok(value)
     }
 
+    /// Unlinks the node for a key, returning it, or null if the key is not found.
     pub fn delete(&self, key: &[u8]) -> *const Node {
+        let mut inner = self.inner.write();
         let mut prev = [ptr::null(); MAX_HEIGHT];
-        let node = self.find_greater_or_equal(key, Some(&mut prev));
-        if self.is_tail(node) {
+        let node = inner.seek(key, &mut prev);
+        if std::ptr::eq(node, inner.tail) {
             return ptr::null();
         }
-        let mut inner = self.inner.write().unwrap();
         unsafe {
-            assert_eq!(
-                inner.comparator.compare((&(*node)).get_key(), &key),
-                cmp::Ordering::Equal,
-                "[skiplist] delete [key={:?}] is not found",
-                &key
-            );
+            if inner.comparator.compare((*node).get_key(), key) != cmp::Ordering::Equal {
+                return ptr::null();
+            }
             let next_node = (*node).get_next(1);
             (*next_node).set_prev(prev[0] as *mut Node);
             let height = (*node).height;
             for i in 1..=height {
                 (*(prev[i - 1] as *mut Node)).set_next(i, (*node).get_next(i));
             }
-            let max_height = inner.max_height;
+            inner.count -= 1;
+            inner.size -= 1;
+            let data_size = (*node).get_key().len() + (*node).get_value().len();
+            inner.data_size -= data_size;
+            inner.node_size -= Node::allocation_size(height);
+            // Levels end at the tail, so a level only the deleted node reached is now empty.
             let head = inner.head;
-            for i in (1..=max_height).rev() {
-                if (*head).get_next(i).is_null() {
-                    inner.max_height -= 1;
-                } else {
+            while inner.max_height > 1
+                && std::ptr::eq((*head).get_next(inner.max_height), inner.tail)
+            {
+                inner.max_height -= 1;
+            }
+            node
+        }
+    }
+
+    /// Unlinks every node in the range under a single write lock, returning the number of
+    /// nodes removed.
+    pub fn range_delete(&self, range: &Range) -> usize {
+        let mut inner = self.inner.write();
+        let head = inner.head as *mut Node;
+        let tail = inner.tail as *mut Node;
+        let cmp = &inner.comparator;
+        let before_start = |key: &[u8]| match &range.start {
+            Bound::Included(k) => cmp.compare(key, k) == cmp::Ordering::Less,
+            Bound::Excluded(k) => cmp.compare(key, k) != cmp::Ordering::Greater,
+            Bound::Unbounded => false,
+        };
+        let before_end = |key: &[u8]| match &range.end {
+            Bound::Included(k) => cmp.compare(key, k) != cmp::Ordering::Greater,
+            Bound::Excluded(k) => cmp.compare(key, k) == cmp::Ordering::Less,
+            Bound::Unbounded => true,
+        };
+
+        let mut removed = 0;
+        let mut data_size = 0;
+        let mut node_size = 0;
+        let mut node = head;
+        unsafe {
+            for level in (1..=inner.max_height).rev() {
+                let mut next = (*node).get_next(level);
+                while next != tail && before_start((*next).get_key()) {
+                    node = next;
+                    next = (*node).get_next(level);
+                }
+                // The nodes in range form a contiguous run at every level, so splice it out.
+                while next != tail && before_end((*next).get_key()) {
+                    if level == 1 {
+                        removed += 1;
+                        data_size += (*next).get_key().len() + (*next).get_value().len();
+                        node_size += Node::allocation_size((*next).height);
+                    }
+                    next = (*next).get_next(level);
+                }
+                (*node).set_next(level, next);
+                if level == 1 {
+                    (*next).set_prev(node);
+                }
+            }
+            while inner.max_height > 1 && (*head).get_next(inner.max_height) == tail {
+                inner.max_height -= 1;
+            }
+        }
+        inner.count -= removed;
+        inner.size -= removed;
+        inner.data_size -= data_size;
+        inner.node_size -= node_size;
+        removed
+    }
+
+    /// Copies up to `batch` entries of the range from `start` to `end` into `out` under a single
+    /// read lock, seeking to `start` under the same lock. Returns false once the range is
+    /// exhausted, i.e. when fewer than `batch` entries were left.
+    pub fn read_batch(
+        &self,
+        start: &Bound<Vec<u8>>,
+        end: &Bound<Vec<u8>>,
+        batch: usize,
+        out: &mut VecDeque<(Vec<u8>, Vec<u8>)>,
+    ) -> bool {
+        let inner = self.inner.read();
+        let mut node = inner.seek_start(start);
+        for _ in 0..batch {
+            if std::ptr::eq(node, inner.tail) {
+                return false;
+            }
+            let key = unsafe { (*node).get_key() };
+            let in_range = match end {
+                Bound::Included(k) => inner.comparator.compare(key, k) != cmp::Ordering::Greater,
+                Bound::Excluded(k) => inner.comparator.compare(key, k) == cmp::Ordering::Less,
+                Bound::Unbounded => true,
+            };
+            if !in_range {
+                return false;
+            }
+            unsafe {
+                out.push_back((*node).get_key_value());
+                node = (*node).get_next(1);
+            }
+        }
+        true
+    }
+
+    /// Calls `f` with the key and value of every node in the range, in order, borrowing them
+    /// straight from the nodes under a single read lock. Stops at the first error. `f` must not
+    /// use the skiplist, which can deadlock on the lock.
+    pub fn for_each<E>(
+        &self,
+        range: &Range,
+        mut f: impl FnMut(&[u8], &[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let inner = self.inner.read();
+        let tail = inner.tail as *mut Node;
+        let mut node = inner.seek_start(&range.start) as *mut Node;
+        unsafe {
+            while node != tail {
+                let key = (*node).get_key();
+                let in_range = match &range.end {
+                    Bound::Included(k) => {
+                        inner.comparator.compare(key, k) != cmp::Ordering::Greater
+                    }
+                    Bound::Excluded(k) => inner.comparator.compare(key, k) == cmp::Ordering::Less,
+                    Bound::Unbounded => true,
+                };
+                if !in_range {
                     break;
                 }
+                f(key, (*node).get_value())?;
+                node = (*node).get_next(1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Estimates the number of keys in a range by counting the nodes in it on the highest level
+    /// with at least `ESTIMATE_SAMPLE` nodes, scaled up by the number of nodes each one stands
+    /// for on average. That level has at most a few times the sample size, so this is cheap.
+    /// Exact if the list is smaller than the sample.
+    pub fn estimate_count(&self, range: &Range) -> usize {
+        let inner = self.inner.read();
+        let tail = inner.tail as *mut Node;
+        let level = inner.sample_level();
+
+        let mut in_range = 0;
+        unsafe {
+            let mut node = (*inner.head).get_next(level);
+            while node != tail {
+                if range.contains_with((*node).get_key(), &inner.comparator) {
+                    in_range += 1;
+                }
+                node = (*node).get_next(level);
+            }
+        }
+        in_range * (BRANCHING as usize).pow(level as u32 - 1)
+    }
+
+    /// Picks up to `parts - 1` keys splitting a range into `parts` sub-ranges of roughly equal
+    /// size, in increasing order. Like `estimate_count`, it only looks at the nodes on a
+    /// sampled level. Returns fewer keys if the range has too few nodes there to split.
+    pub fn split_keys(&self, range: &Range, parts: usize) -> Vec<Vec<u8>> {
+        let inner = self.inner.read();
+        let tail = inner.tail as *mut Node;
+        let level = inner.sample_level();
+
+        let mut sample = Vec::new();
+        unsafe {
+            let mut node = (*inner.head).get_next(level);
+            while node != tail {
+                if range.contains_with((*node).get_key(), &inner.comparator) {
+                    sample.push(node);
+                }
+                node = (*node).get_next(level);
             }
-            node
         }
+        // Never split at the first sampled key, which may be the start of the range.
+        let mut keys: Vec<Vec<u8>> = (1..parts.max(1))
+            .map(|i| i * sample.len() / parts)
+            .filter(|&i| i > 0)
+            .map(|i| unsafe { (*sample[i]).get_key().to_vec() })
+            .collect();
+        keys.dedup();
+        keys
+    }
+
+    /// Walks the list checking its invariants: keys strictly increase at every level, each
+    /// node's successor points back to it, nodes only appear on levels up to their height, and
+    /// the count matches the nodes found. Returns a description of the first violation.
+    pub fn verify(&self) -> Result<(), String> {
+        let inner = self.inner.read();
+        let head = inner.head as *mut Node;
+        let tail = inner.tail as *mut Node;
+        let mut count = 0;
+        unsafe {
+            for level in 1..=inner.max_height {
+                let mut node = head;
+                loop {
+                    let next = (*node).get_next(level);
+                    if next.is_null() {
+                        return Err(format!("null next pointer at level {}", level));
+                    }
+                    if level == 1 && (*next).get_prev() != node {
+                        return Err(format!(
+                            "prev pointer of {:?} does not point back to its predecessor",
+                            (*next).get_key()
+                        ));
+                    }
+                    if next == tail {
+                        break;
+                    }
+                    if (*next).height < level {
+                        return Err(format!(
+                            "{:?} of height {} is linked at level {}",
+                            (*next).get_key(),
+                            (*next).height,
+                            level
+                        ));
+                    }
+                    if node != head
+                        && inner
+                            .comparator
+                            .compare((*node).get_key(), (*next).get_key())
+                            != cmp::Ordering::Less
+                    {
+                        return Err(format!(
+                            "{:?} is not ordered before {:?} at level {}",
+                            (*node).get_key(),
+                            (*next).get_key(),
+                            level
+                        ));
+                    }
+                    if level == 1 {
+                        count += 1;
+                    }
+                    node = next;
+                }
+            }
+        }
+        if count != inner.count || count != inner.size {
+            return Err(format!(
+                "found {} nodes, but count is {} and size is {}",
+                count, inner.count, inner.size
+            ));
+        }
+        Ok(())
     }
 
     fn find_greater_or_equal(
@@ -288,13 +814,13 @@ impl<C: Comparator, A: Arena> Skiplist<C, A> {
         key: &[u8],
         mut prev_nodes: Option<&mut [*const Node]>,
     ) -> *const Node {
-        let inner = self.inner.read().unwrap();
+        let inner = self.inner.read();
         let mut level = inner.max_height;
         let mut node = inner.head;
         loop {
             unsafe {
                 let next = (*node).get_next(level);
-                if self.key_is_less_than_or_equal(key, next) {
+                if inner.key_is_less_than_or_equal(key, next) {
                     if let Some(ref mut p) = prev_nodes {
                         p[level - 1] = node;
                     }
@@ -309,14 +835,45 @@ impl<C: Comparator, A: Arena> Skiplist<C, A> {
         }
     }
 
+    /// Like `get_greater_or_equal`, but descends from a hint node instead of the head, starting
+    /// at the hint's own height. Falls back to a search from the head if the hint is not before
+    /// the key. Cheap for callers that advance through the list monotonically.
+    ///
+    /// # Safety
+    ///
+    /// `hint` must be null or a node that is linked into this skiplist, such as one just
+    /// returned by one of its lookups and not deleted since.
+    pub unsafe fn find_greater_or_equal_from(&self, hint: *const Node, key: &[u8]) -> *const Node {
+        let inner = self.inner.read();
+        let (mut node, mut level) = if hint.is_null()
+            || std::ptr::eq(hint, inner.head)
+            || inner.key_is_less_than_or_equal(key, hint)
+        {
+            (inner.head, inner.max_height)
+        } else {
+            (hint, (*hint).height)
+        };
+        loop {
+            let next = (*node).get_next(level);
+            if inner.key_is_less_than_or_equal(key, next) {
+                if level == 1 {
+                    return next;
+                }
+                level -= 1;
+            } else {
+                node = next;
+            }
+        }
+    }
+
     pub fn get_less_or_equal(&self, key: &[u8]) -> *const Node {
-        let inner = self.inner.read().unwrap();
+        let inner = self.inner.read();
         let mut level = inner.max_height;
         let mut node = inner.head;
         loop {
             unsafe {
                 let next = (*node).get_next(level);
-                if self.is_tail(next)
+                if std::ptr::eq(next, inner.tail)
                     || inner.comparator.compare((*next).get_key(), key) == cmp::Ordering::Greater
                 {
                     if level == 1 {
@@ -332,34 +889,22 @@ impl<C: Comparator, A: Arena> Skiplist<C, A> {
     }
 
     pub fn get_first(&self) -> *const Node {
-        let inner = self.inner.read().unwrap();
+        let inner = self.inner.read();
 
         unsafe { (*inner.head).get_next(1) }
     }
 
     pub fn get_last(&self) -> *const Node {
-        let inner = self.inner.read().unwrap();
+        let inner = self.inner.read();
         unsafe { (*inner.tail).get_prev() }
     }
 
     pub fn key_is_less_than_or_equal(&self, key: &[u8], n: *const Node) -> bool {
-        let inner = self.inner.read().unwrap();
-
-        if std::ptr::eq(n, inner.head) {
-            false
-        } else if std::ptr::eq(n, inner.tail) {
-            true
-        } else {
-            let node_key = unsafe { (*n).get_key() };
-            !matches!(
-                inner.comparator.compare(key, node_key),
-                cmp::Ordering::Greater
-            )
-        }
+        self.inner.read().key_is_less_than_or_equal(key, n)
     }
 
     pub fn key_is_greater_than_or_equal(&self, key: &[u8], n: *const Node) -> bool {
-        let inner = self.inner.read().unwrap();
+        let inner = self.inner.read();
         if std::ptr::eq(n, inner.head) {
             true
         } else if std::ptr::eq(n, inner.tail) {
@@ -371,7 +916,7 @@ impl<C: Comparator, A: Arena> Skiplist<C, A> {
     }
 
     pub fn key_is_less_than(&self, key: &[u8], n: *const Node) -> bool {
-        let inner = self.inner.read().unwrap();
+        let inner = self.inner.read();
         if std::ptr::eq(n, inner.head) {
             false
         } else if std::ptr::eq(n, inner.tail) {
@@ -383,7 +928,7 @@ impl<C: Comparator, A: Arena> Skiplist<C, A> {
     }
 
     pub fn key_is_greater_than(&self, key: &[u8], n: *const Node) -> bool {
-        let inner = self.inner.read().unwrap();
+        let inner = self.inner.read();
         if std::ptr::eq(n, inner.head) {
             true
         } else if std::ptr::eq(n, inner.tail) {
@@ -398,12 +943,12 @@ impl<C: Comparator, A: Arena> Skiplist<C, A> {
     }
 
     pub fn is_head(&self, n: *const Node) -> bool {
-        let inner = self.inner.read().unwrap();
+        let inner = self.inner.read();
         std::ptr::eq(n, inner.head)
     }
 
     pub fn is_tail(&self, n: *const Node) -> bool {
-        let inner = self.inner.read().unwrap();
+        let inner = self.inner.read();
         std::ptr::eq(n, inner.tail)
     }
 }
@@ -424,6 +969,244 @@ fn rand_height() -> usize {
 mod test {
 
     use super::*;
+
+    fn assert_no_starvation(lock: LockKind) {
+        let skiplist =
+            Skiplist::new_with_lock(BytewiseComparator::default(), BlockArena::default(), lock);
+        for i in 0..1000u32 {
+            skiplist.insert(&i.to_be_bytes(), &[0; 8]);
+        }
+        let keys = (0..1000u32).map(|i| i.to_be_bytes()).collect::<Vec<_>>();
+        let keys = keys.iter().map(|k| &k[..]).collect::<Vec<_>>();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(200);
+
+        let (reads, writes) = std::thread::scope(|scope| {
+            let readers = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut reads = 0;
+                        while std::time::Instant::now() < deadline {
+                            assert_eq!(skiplist.get_many(&keys).len(), 1000);
+                            reads += 1;
+                        }
+                        reads
+                    })
+                })
+                .collect::<Vec<_>>();
+            let writer = scope.spawn(|| {
+                let mut writes = 0u32;
+                while std::time::Instant::now() < deadline {
+                    skiplist.insert(&(1000 + writes).to_be_bytes(), &[1; 8]);
+                    writes += 1;
+                }
+                writes
+            });
+            let reads = readers
+                .into_iter()
+                .map(|r| r.join().unwrap())
+                .collect::<Vec<_>>();
+            (reads, writer.join().unwrap())
+        });
+        assert!(reads.iter().all(|&r| r > 0), "reader starved: {:?}", reads);
+        assert!(writes > 0, "writer starved");
+        assert_eq!(skiplist.count(), 1000 + writes as usize);
+    }
+
+    #[test]
+    fn test_no_starvation() {
+        assert_no_starvation(LockKind::Std);
+        #[cfg(feature = "fair_lock")]
+        assert_no_starvation(LockKind::ParkingLot);
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let skiplist = Skiplist::new(BytewiseComparator::default(), BlockArena::default());
+        std::thread::scope(|scope| {
+            for t in 0..4u32 {
+                let skiplist = &skiplist;
+                scope.spawn(move || {
+                    // Interleave the threads' keys, so they keep writing next to each other.
+                    for i in 0..2000u32 {
+                        skiplist.insert(&(i * 4 + t).to_be_bytes(), &[1; 8]);
+                        if i % 2 == 1 {
+                            assert!(!skiplist.delete(&((i - 1) * 4 + t).to_be_bytes()).is_null());
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(skiplist.count(), 4000);
+        assert_eq!(skiplist.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_poisoned_lock() {
+        let skiplist = Skiplist::new(BytewiseComparator::default(), BlockArena::default());
+        skiplist.insert(b"a", b"1");
+        // Panic while holding the write lock, poisoning it.
+        std::thread::scope(|scope| {
+            let result = scope.spawn(|| skiplist.get_or_insert_with(b"b", || panic!("boom")));
+            assert!(result.join().is_err());
+        });
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                assert_eq!(
+                    skiplist.get_entry(b"a"),
+                    Some((b"a".to_vec(), b"1".to_vec()))
+                );
+                assert!(skiplist.get(b"b").is_null());
+                skiplist.insert(b"c", b"3");
+            });
+        });
+        assert_eq!(skiplist.count(), 2);
+        assert_eq!(skiplist.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_from_sorted() {
+        let entries = (0..10000u32).map(|i| (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec()));
+        let skiplist = Skiplist::from_sorted(
+            BytewiseComparator::default(),
+            BlockArena::default(),
+            entries,
+        );
+        assert_eq!(skiplist.count(), 10000);
+        skiplist.verify().unwrap();
+
+        let mut node = skiplist.get_first();
+        for i in 0..10000u32 {
+            unsafe {
+                assert_eq!((*node).get_key(), i.to_be_bytes());
+                node = (*node).get_next_at_first_level();
+            }
+        }
+        assert!(skiplist.is_tail(node));
+        for i in (0..10000u32).step_by(7) {
+            let node = skiplist.get(&i.to_be_bytes());
+            assert_eq!(unsafe { (*node).get_value() }, i.to_le_bytes());
+        }
+        assert!(skiplist.get(&10000u32.to_be_bytes()).is_null());
+
+        // The list stays usable for regular inserts afterwards.
+        skiplist.insert(&[0, 0, 0, 0, 1], &[]);
+        skiplist.insert(&[0xff; 5], &[]);
+        assert_eq!(skiplist.count(), 10002);
+        skiplist.verify().unwrap();
+    }
+
+    #[test]
+    fn test_for_each() {
+        let skiplist = Skiplist::new(BytewiseComparator::default(), BlockArena::default());
+        for i in 0..100u8 {
+            skiplist.insert(&[i], &[i; 4]);
+        }
+        let range = Range::from(vec![10]..vec![20]);
+        let mut sum = 0u64;
+        let mut keys = 0;
+        skiplist
+            .for_each(&range, |key, value| {
+                keys += 1;
+                sum += key[0] as u64 + value.iter().map(|&b| b as u64).sum::<u64>();
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        assert_eq!(keys, 10);
+        assert_eq!(sum, (10..20).map(|i| 5 * i).sum::<u64>());
+
+        // An error stops the walk.
+        let mut seen = 0;
+        let result = skiplist.for_each(&Range::from(vec![50]..), |key, _| {
+            seen += 1;
+            if key[0] == 52 {
+                return Err(key[0]);
+            }
+            Ok(())
+        });
+        assert_eq!(result, Err(52));
+        assert_eq!(seen, 3);
+    }
+
+    #[test]
+    fn test_delete_count() {
+        let skiplist = Skiplist::new(BytewiseComparator::default(), BlockArena::default());
+        for i in 0..10 {
+            skiplist.insert(&vec![i], &vec![i]);
+        }
+        assert!(!skiplist.delete(&vec![3]).is_null());
+        assert!(!skiplist.delete(&vec![7]).is_null());
+        assert_eq!(skiplist.count(), 8);
+
+        // Deleting an absent key, before, between or after the others, changes nothing.
+        assert!(skiplist.delete(&vec![3]).is_null());
+        assert!(skiplist.delete(&vec![4, 0]).is_null());
+        assert!(skiplist.delete(&vec![20]).is_null());
+        assert_eq!(skiplist.count(), 8);
+
+        skiplist.insert(&vec![3], &vec![3]);
+        skiplist.insert(&vec![4], &vec![40]);
+        assert!(!skiplist.delete(&vec![0]).is_null());
+        assert_eq!(skiplist.count(), 8);
+        assert_eq!(skiplist.total_size(), 8);
+        skiplist.verify().unwrap();
+    }
+
+    #[test]
+    fn test_delete_shrinks_height() {
+        let skiplist = Skiplist::new(BytewiseComparator::default(), BlockArena::default());
+        for i in 0..100 {
+            skiplist.insert(&vec![i], &vec![i]);
+        }
+        // Returns the height and key of the tallest node.
+        let tallest = || {
+            let mut tallest = (0, vec![]);
+            let mut node = skiplist.get_first();
+            while !skiplist.is_tail(node) {
+                unsafe {
+                    if (*node).height > tallest.0 {
+                        tallest = ((*node).height, (*node).get_key().to_vec());
+                    }
+                    node = (*node).get_next_at_first_level();
+                }
+            }
+            tallest
+        };
+        // Delete the tallest node each time, so that the height has to shrink.
+        while skiplist.count() > 0 {
+            let (height, key) = tallest();
+            assert_eq!(skiplist.inner.read().max_height, height);
+            assert!(!skiplist.delete(&key).is_null());
+        }
+        assert_eq!(skiplist.inner.read().max_height, 1);
+        skiplist.verify().unwrap();
+    }
+
+    #[test]
+    fn test_overwrite_same_length() {
+        let skiplist = Skiplist::new(BytewiseComparator::default(), BlockArena::default());
+        for i in 0..10 {
+            skiplist.insert(&vec![i], &vec![i; 16]);
+        }
+        let value = skiplist.get(&vec![5]);
+        let buffer = unsafe { (*value).get_value().as_ptr() };
+
+        skiplist.insert(&vec![5], &[0xff; 16]);
+
+        let node = skiplist.get(&vec![5]);
+        assert!(std::ptr::eq(node, value));
+        unsafe {
+            assert_eq!((*node).get_value(), &[0xff; 16]);
+            assert!(std::ptr::eq((*node).get_value().as_ptr(), buffer));
+        }
+
+        skiplist.insert(&vec![5], &[0xee; 32]);
+        assert_eq!(
+            unsafe { (*skiplist.get(&vec![5])).get_value() },
+            &[0xee; 32]
+        );
+    }
+
     #[test]
     fn test_skiplist() {
         let skiplist = Skiplist::new(BytewiseComparator::default(), BlockArena::default());
@@ -438,6 +1221,28 @@ mod test {
         print_skiplist_reverse(head);
     }
 
+    #[test]
+    fn test_find_greater_or_equal_from() {
+        let skiplist = Skiplist::new(BytewiseComparator::default(), BlockArena::default());
+        for i in (0..200).step_by(2) {
+            skiplist.insert(&vec![i], &vec![i]);
+        }
+        let mut hint = skiplist.get_first();
+        for i in 0..=200 {
+            let expect = skiplist.get_greater_or_equal(&vec![i]);
+            let found = unsafe { skiplist.find_greater_or_equal_from(hint, &vec![i]) };
+            assert!(std::ptr::eq(found, expect), "mismatch for key {}", i);
+            if !skiplist.is_tail(found) {
+                hint = unsafe { (*found).get_prev() };
+            }
+        }
+
+        // A hint past the key falls back to a full search.
+        let hint = skiplist.get(&vec![100]);
+        let found = unsafe { skiplist.find_greater_or_equal_from(hint, &vec![11]) };
+        assert!(std::ptr::eq(found, skiplist.get(&vec![12])));
+    }
+
     fn print_skiplist(mut head: *mut Node) {
         unsafe {
             while !head.is_null() {
@@ -454,4 +1259,22 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_get_entry() {
+        let skiplist = Skiplist::new(BytewiseComparator::default(), BlockArena::default());
+        skiplist.insert(b"a", b"1");
+        skiplist.insert(b"c", b"3");
+        assert_eq!(
+            skiplist.get_entry(b"a"),
+            Some((b"a".to_vec(), b"1".to_vec()))
+        );
+        assert_eq!(
+            skiplist.get_entry(b"c"),
+            Some((b"c".to_vec(), b"3".to_vec()))
+        );
+        assert_eq!(skiplist.get_entry(b"b"), None);
+        assert_eq!(skiplist.get_entry(b"d"), None);
+        assert_eq!(skiplist.get_entry(b""), None);
+    }
 }
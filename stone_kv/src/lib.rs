@@ -1,2 +1,3 @@
-mod kv;
-mod log;
\ No newline at end of file
+pub mod error;
+pub mod kv;
+mod log;
@@ -1,16 +1,27 @@
-use std::cmp::{max, min};
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::cmp::max;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Formatter;
-use std::fs::{create_dir_all, File, OpenOptions};
+use std::fs::{create_dir_all, rename, File, OpenOptions};
 use std::io;
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
-use std::sync::{Mutex, MutexGuard};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 
 use super::*;
 
 use bytes::Bytes;
 
+use crate::kv;
+use crate::kv::key::KeyBuilder;
+
+/// The metadata namespace used by the LogStore metadata methods.
+const DEFAULT_NAMESPACE: &str = "";
+
+/// The default namespace's metadata and the other namespaces', as kept by `Hybrid`.
+type Metadata = (BTreeMap<Vec<u8>, Vec<u8>>, BTreeMap<Vec<u8>, Vec<u8>>);
+
 pub struct Hybrid<F>
 where
     F: Read + Write + Seek,
@@ -18,9 +29,15 @@ where
     file: Mutex<F>,
     index: BTreeMap<u64, (u64, u32)>,
     uncommitted: VecDeque<Bytes>,
-    metadata: HashMap<Vec<u8>, Vec<u8>>,
-    metadata_file: F,
+    /// Metadata in the default namespace, keyed by the raw key as it was before namespaces
+    /// existed, so that previously written keys stay readable.
+    metadata: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Metadata in the other namespaces, keyed by namespace and key as encoded by
+    /// `metadata_key`.
+    namespaced: BTreeMap<Vec<u8>, Vec<u8>>,
+    metadata_path: PathBuf,
     sync: bool,
+    group_sync: Arc<GroupSync>,
 }
 
 impl Hybrid<File> {
@@ -32,64 +49,49 @@ impl Hybrid<File> {
             .create(true)
             .open(dir.join("raft-log"))?;
 
-        let metadata_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(dir.join("raft-metadata"))?;
+        let metadata_path = dir.join("raft-metadata");
+        let group_sync = Arc::new(GroupSync::new(file.try_clone()?));
+        let (metadata, namespaced) = Self::load_metadata(&metadata_path)?;
 
         Ok(Self {
             index: Self::build_index(&file)?,
             file: Mutex::new(file),
             uncommitted: VecDeque::new(),
-            metadata: Self::load_metadata(&metadata_file)?,
-            metadata_file,
+            metadata,
+            namespaced,
+            metadata_path,
             sync,
+            group_sync,
         })
     }
 
-    fn build_index(file: &File) -> Result<BTreeMap<u64, (u64, u32)>> {
-        let filesize = file.metadata()?.len();
-        let mut bufreader = BufReader::new(file);
-        let mut index = BTreeMap::new();
-        let mut sizebuf = [0; 4];
-        let mut pos = 0;
-        let mut i = 1;
-        while pos < filesize {
-            bufreader.read_exact(&mut sizebuf)?;
-            pos += 4;
-            let size = u32::from_be_bytes(sizebuf);
-            index.insert(i, (pos, size));
-            let mut buf = vec![0; size as usize];
-            bufreader.read_exact(&mut buf)?;
-            pos += size as u64;
-            i += 1;
+    /// Removes every entry, committed or not, and truncates the log file to zero. Metadata is
+    /// kept. Used when installing a snapshot that supersedes the entire log.
+    pub fn reset(&mut self) -> Result<()> {
+        let file = self.file.get_mut().unwrap();
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        if self.sync {
+            file.sync_all()?;
         }
-        Ok(index)
+        self.index.clear();
+        self.uncommitted.clear();
+        Ok(())
     }
 
-    fn load_metadata(file: &File) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
-        match bincode::deserialize_from(file) {
-            Ok(metadata) => Ok(metadata),
-            Err(err) => {
-                if let bincode::ErrorKind::Io(err) = &*err {
-                    if err.kind() == std::io::ErrorKind::UnexpectedEof {
-                        return Ok(HashMap::new());
-                    }
-                }
-                Err(err.into())
-            }
-        }
+    /// Returns the nth pending uncommitted entry, counting from zero, without committing it.
+    pub fn peek_uncommitted(&self, n: usize) -> Option<&Bytes> {
+        self.uncommitted.get(n)
     }
-}
 
-impl LogStore for Hybrid<File> {
-    fn append(&mut self, entry: Bytes) -> Result<u64> {
-        self.uncommitted.push_back(entry);
-        Ok(self.len())
+    /// Returns the number of pending uncommitted entries.
+    pub fn uncommitted_len(&self) -> usize {
+        self.uncommitted.len()
     }
 
-    fn commit(&mut self, index: u64) -> Result<()> {
+    /// Commits entries like `LogStore::commit`, returning the log index and file offset of
+    /// each entry committed by this call, for building external indexes over the log file.
+    pub fn commit_returning_offsets(&mut self, index: u64) -> Result<Vec<(u64, u64)>> {
         if index > self.len() {
             return Err(anyhow!("Cannot commit non-existant index {}", index));
         }
@@ -100,17 +102,19 @@ impl LogStore for Hybrid<File> {
             ));
         }
         if index == self.index.len() as u64 {
-            return Ok(());
+            return Ok(Vec::new());
         }
         let mut file = self.file.lock().unwrap();
         let mut pos = file.seek(SeekFrom::End(0))?;
         let mut bufwriter = BufWriter::new(&mut *file);
+        let mut offsets = Vec::new();
         for i in (self.index.len() as u64 + 1)..=index {
             match self.uncommitted.pop_front() {
                 Some(entry) => {
                     bufwriter.write_all(&(entry.len() as u32).to_be_bytes())?;
                     pos += 4;
                     self.index.insert(i, (pos, entry.len() as u32));
+                    offsets.push((i, pos));
                     bufwriter.write_all(entry.as_ref())?;
                     pos += entry.len() as u64;
                 }
@@ -122,10 +126,181 @@ impl LogStore for Hybrid<File> {
         bufwriter.flush()?;
         drop(bufwriter);
         if self.sync {
-            file.sync_data()?;
+            self.group_sync.sync()?;
+        }
+        Ok(offsets)
+    }
+
+    /// Gets a metadata value from a namespace.
+    pub fn get_metadata_ns(&self, ns: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = match ns {
+            DEFAULT_NAMESPACE => self.metadata.get(key),
+            ns => self.namespaced.get(&metadata_key(ns, key)),
+        };
+        Ok(value.cloned())
+    }
+
+    /// Sets a metadata value in a namespace. Namespaces keep separate users of the metadata,
+    /// such as Raft's own state and the application's, from overwriting each other's keys.
+    pub fn set_metadata_ns(&mut self, ns: &str, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        match ns {
+            DEFAULT_NAMESPACE => self.metadata.insert(key, value),
+            ns => self.namespaced.insert(metadata_key(ns, &key), value),
+        };
+        self.write_metadata()
+    }
+
+    /// Returns a handle for syncing the log file that can be shared between threads, so that
+    /// writers can commit under a lock and sync after releasing it. Concurrent syncs through
+    /// it are coalesced. See `GroupSync`.
+    pub fn group_sync(&self) -> Arc<GroupSync> {
+        self.group_sync.clone()
+    }
+
+    /// Returns the number of fsyncs of the log file made through the group sync, including
+    /// those by commit and sync.
+    pub fn fsync_count(&self) -> u64 {
+        self.group_sync.fsync_count()
+    }
+
+    /// Scans the log between the given indexes, like `scan`, but returns a `LogScan` that can
+    /// be repositioned with `seek`.
+    pub fn log_scan(&self, range: Range) -> LogScan<'_> {
+        let start = match range.start {
+            Bound::Included(0) => 1,
+            Bound::Included(n) => n,
+            Bound::Excluded(n) => n + 1,
+            Bound::Unbounded => 1,
+        };
+        let end = match range.end {
+            Bound::Included(n) => n,
+            Bound::Excluded(0) => 0,
+            Bound::Excluded(n) => n - 1,
+            Bound::Unbounded => self.len(),
+        };
+        LogScan {
+            log: self,
+            reader: None,
+            start,
+            next: start,
+            end,
+        }
+    }
+
+    /// Iterates over the default namespace's metadata pairs in a key range, in key order.
+    pub fn scan_metadata(&self, range: kv::Range) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.metadata
+            .range(range)
+            .map(|(k, v)| (k.as_slice(), v.as_slice()))
+    }
+
+    fn build_index(file: &File) -> Result<BTreeMap<u64, (u64, u32)>> {
+        let filesize = file.metadata()?.len();
+        let mut bufreader = BufReader::new(file);
+        let mut index = BTreeMap::new();
+        let mut sizebuf = [0; 4];
+        let mut pos = 0;
+        let mut i = 1;
+        while pos < filesize {
+            bufreader.read_exact(&mut sizebuf)?;
+            pos += 4;
+            let size = u32::from_be_bytes(sizebuf);
+            index.insert(i, (pos, size));
+            let mut buf = vec![0; size as usize];
+            bufreader.read_exact(&mut buf)?;
+            pos += size as u64;
+            i += 1;
+        }
+        Ok(index)
+    }
+
+    /// Loads the metadata file, falling back to a fully written temp file if the main file
+    /// fails its checksum.
+    fn load_metadata(path: &Path) -> Result<Metadata> {
+        match Self::read_metadata(path) {
+            Ok(metadata) => Ok(metadata.unwrap_or_default()),
+            Err(err) => match Self::read_metadata(&path.with_extension("tmp")) {
+                Ok(Some(metadata)) => Ok(metadata),
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// Reads a checksummed metadata file, returning None if it is missing or empty. Files
+    /// written before the checksum was added are plain bincode, and are read as such if they
+    /// fail the checksum but decode in full; they're rewritten with one on the next write.
+    fn read_metadata(path: &Path) -> Result<Option<Metadata>> {
+        let buf = match std::fs::read(path) {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        if buf.len() >= 4 {
+            let (crcbuf, payload) = buf.split_at(4);
+            if u32::from_be_bytes(crcbuf.try_into()?) == crc32fast::hash(payload) {
+                return Ok(Some(Self::decode_metadata(payload)?));
+            }
+        }
+        let mut legacy = buf.as_slice();
+        match bincode::deserialize_from(&mut legacy) {
+            Ok(metadata) if legacy.is_empty() => Ok(Some((metadata, BTreeMap::new()))),
+            _ if buf.len() < 4 => Err(anyhow!("Metadata file {} is truncated", path.display())),
+            _ => Err(anyhow!(
+                "Metadata file {} has a checksum mismatch",
+                path.display()
+            )),
+        }
+    }
+
+    /// Decodes the default namespace's map followed by the other namespaces' map. Files written
+    /// before namespaces existed end after the first map.
+    fn decode_metadata(mut payload: &[u8]) -> Result<Metadata> {
+        let metadata = bincode::deserialize_from(&mut payload)?;
+        let namespaced = match payload.is_empty() {
+            true => BTreeMap::new(),
+            false => bincode::deserialize_from(&mut payload)?,
+        };
+        Ok((metadata, namespaced))
+    }
+
+    /// Writes the metadata to a temp file and atomically renames it over the metadata file,
+    /// so a crash mid-write leaves the previous copy intact. With sync set, the directory is
+    /// synced too, since the rename isn't durable until its directory entry is.
+    fn write_metadata(&self) -> Result<()> {
+        let payload = bincode::serialize(&(&self.metadata, &self.namespaced))?;
+        let tmp_path = self.metadata_path.with_extension("tmp");
+        let mut tmp = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(&crc32fast::hash(&payload).to_be_bytes())?;
+        tmp.write_all(&payload)?;
+        if self.sync {
+            tmp.sync_data()?;
+        }
+        rename(&tmp_path, &self.metadata_path)?;
+        if self.sync {
+            if let Some(dir) = self.metadata_path.parent() {
+                File::open(dir)?.sync_all()?;
+            }
         }
         Ok(())
     }
+}
+
+impl LogStore for Hybrid<File> {
+    fn append(&mut self, entry: Bytes) -> Result<u64> {
+        self.uncommitted.push_back(entry);
+        Ok(self.len())
+    }
+
+    fn commit(&mut self, index: u64) -> Result<()> {
+        self.commit_returning_offsets(index).map(|_| ())
+    }
 
     fn committed(&self) -> u64 {
         self.index.len() as u64
@@ -158,54 +333,7 @@ impl LogStore for Hybrid<File> {
     }
 
     fn scan(&self, range: Range) -> Scan {
-        let start = match range.start {
-            Bound::Included(0) => 1,
-            Bound::Included(n) => n,
-            Bound::Excluded(n) => n + 1,
-            Bound::Unbounded => 1,
-        };
-        let end = match range.end {
-            Bound::Included(n) => n,
-            Bound::Excluded(0) => 0,
-            Bound::Excluded(n) => n - 1,
-            Bound::Unbounded => self.len(),
-        };
-
-        let mut scan: Scan = Box::new(std::iter::empty());
-        if start > end {
-            return scan;
-        }
-
-        // Scan committed entries in file
-        if let Some((offset, _)) = self.index.get(&start) {
-            let mut file = self.file.lock().unwrap();
-            file.seek(SeekFrom::Start(*offset - 4)).unwrap(); // seek to length prefix
-            let mut bufreader = BufReader::new(MutexReader(file)); // FIXME Avoid MutexReader
-            scan = Box::new(scan.chain(self.index.range(start..=end).map(
-                move |(_, (_, size))| {
-                    let mut sizebuf = vec![0; 4];
-                    bufreader.read_exact(&mut sizebuf)?;
-                    let mut buf = vec![0; *size as usize];
-                    bufreader.read_exact(&mut buf)?;
-                    Ok(Bytes::from(buf))
-                },
-            )));
-        }
-
-        // Scan uncommitted entries in memory
-        if end > self.index.len() as u64 {
-            scan = Box::new(
-                scan.chain(
-                    self.uncommitted
-                        .iter()
-                        .skip(start as usize - min(start as usize, self.index.len() + 1))
-                        .take(end as usize - max(start as usize, self.index.len()) + 1)
-                        .cloned()
-                        .map(Ok),
-                ),
-            )
-        }
-        scan
+        Box::new(self.log_scan(range))
     }
 
     fn size(&self) -> u64 {
@@ -227,20 +355,95 @@ impl LogStore for Hybrid<File> {
         Ok(self.len())
     }
 
+    fn sync(&mut self) -> Result<()> {
+        self.group_sync.sync()?;
+        match File::open(&self.metadata_path) {
+            Ok(metadata) => metadata.sync_data()?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        Ok(())
+    }
+
     fn get_metadata(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        Ok(self.metadata.get(key).cloned())
+        self.get_metadata_ns(DEFAULT_NAMESPACE, key)
     }
 
     fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        self.metadata.insert(key, value);
-        self.metadata_file.set_len(0)?;
-        self.metadata_file.seek(SeekFrom::Start(0))?;
-        bincode::serialize_into(&mut self.metadata_file, &self.metadata)?;
-        if self.sync {
-            self.metadata_file.sync_data()?;
+        self.set_metadata_ns(DEFAULT_NAMESPACE, key, value)
+    }
+}
+
+/// Encodes a key in a namespace other than the default as the escaped namespace followed by
+/// the raw key, so that namespaces can't collide and each one's keys are contiguous and in key
+/// order.
+fn metadata_key(ns: &str, key: &[u8]) -> Vec<u8> {
+    let mut encoded = KeyBuilder::new().push_str(ns).build();
+    encoded.extend_from_slice(key);
+    encoded
+}
+
+/// Syncs a file on behalf of concurrent writers, coalescing their fsyncs. A writer that asks
+/// for a sync while one is in progress waits for it to finish, and then the first waiter
+/// runs a single fsync that covers everyone who arrived in the meantime. The in-progress
+/// fsync can't cover them itself, since their writes may have come after it started.
+pub struct GroupSync {
+    file: File,
+    state: Mutex<GroupSyncState>,
+    synced: Condvar,
+    fsyncs: AtomicU64,
+}
+
+#[derive(Default)]
+struct GroupSyncState {
+    /// Sync requests issued so far. Each request's ticket is the count after it.
+    requested: u64,
+    /// All requests up to this ticket are durable.
+    completed: u64,
+    in_progress: bool,
+}
+
+impl GroupSync {
+    fn new(file: File) -> Self {
+        Self {
+            file,
+            state: Mutex::default(),
+            synced: Condvar::new(),
+            fsyncs: AtomicU64::new(0),
+        }
+    }
+
+    /// Makes everything written to the file before the call durable.
+    pub fn sync(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.requested += 1;
+        let ticket = state.requested;
+        while state.completed < ticket {
+            if state.in_progress {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            state.in_progress = true;
+            let covered = state.requested;
+            drop(state);
+            let result = self.file.sync_data();
+            self.fsyncs.fetch_add(1, Ordering::Relaxed);
+            state = self.state.lock().unwrap();
+            state.in_progress = false;
+            if result.is_ok() {
+                state.completed = max(state.completed, covered);
+            }
+            // On failure, the waiters retry with a new fsync of their own.
+            self.synced.notify_all();
+            result?;
         }
         Ok(())
     }
+
+    /// Returns the number of fsyncs made.
+    pub fn fsync_count(&self) -> u64 {
+        self.fsyncs.load(Ordering::Relaxed)
+    }
 }
 
 struct MutexReader<'a>(MutexGuard<'a, File>);
@@ -250,3 +453,403 @@ impl<'a> Read for MutexReader<'a> {
         self.0.read(buf)
     }
 }
+
+impl<'a> Seek for MutexReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+/// A scan over a range of log entries, which can be repositioned with `seek`. The log file is
+/// locked from the first committed entry read until the scan is dropped.
+pub struct LogScan<'a> {
+    log: &'a Hybrid<File>,
+    /// The file reader, and the file position it's at if known.
+    reader: Option<(BufReader<MutexReader<'a>>, Option<u64>)>,
+    start: u64,
+    next: u64,
+    end: u64,
+}
+
+impl<'a> LogScan<'a> {
+    /// Positions the scan so that the next entry yielded is the one at `index`. Indexes before
+    /// the start of the range seek to the start, and indexes past the end end the scan.
+    pub fn seek(&mut self, index: u64) {
+        self.next = max(index, self.start);
+    }
+
+    fn read_committed(&mut self, offset: u64, size: u32) -> Result<Bytes> {
+        let log = self.log;
+        let (reader, pos) = self
+            .reader
+            .get_or_insert_with(|| (BufReader::new(MutexReader(log.file.lock().unwrap())), None));
+        // Sequential reads just step over the length prefix; otherwise skip to the entry.
+        if *pos == Some(offset - 4) {
+            reader.read_exact(&mut [0; 4])?;
+        } else {
+            reader.seek(SeekFrom::Start(offset))?;
+        }
+        let mut buf = vec![0; size as usize];
+        reader.read_exact(&mut buf)?;
+        *pos = Some(offset + size as u64);
+        Ok(Bytes::from(buf))
+    }
+}
+
+impl<'a> Iterator for LogScan<'a> {
+    type Item = Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next > self.end {
+            return None;
+        }
+        let index = self.next;
+        self.next += 1;
+        if let Some(&(offset, size)) = self.log.index.get(&index) {
+            return Some(self.read_committed(offset, size));
+        }
+        // Entry i lives at uncommitted[i - first_uncommitted].
+        let first_uncommitted = self.log.index.len() as u64 + 1;
+        let entry = index
+            .checked_sub(first_uncommitted)
+            .and_then(|i| self.log.uncommitted.get(i as usize));
+        match entry {
+            Some(entry) => Some(Ok(entry.clone())),
+            None => {
+                self.next = self.end + 1;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_metadata_survives_torn_write() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut log = Hybrid::open_from_dir_path(dir.path(), true)?;
+        log.set_metadata(b"term".to_vec(), vec![1])?;
+        drop(log);
+
+        // Simulate a crash halfway through writing the next copy.
+        let good = std::fs::read(dir.path().join("raft-metadata"))?;
+        std::fs::write(
+            dir.path().join("raft-metadata.tmp"),
+            &good[..good.len() / 2],
+        )?;
+
+        let log = Hybrid::open_from_dir_path(dir.path(), true)?;
+        assert_eq!(log.get_metadata(b"term")?, Some(vec![1]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_checksum_mismatch() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut log = Hybrid::open_from_dir_path(dir.path(), true)?;
+        log.set_metadata(b"term".to_vec(), vec![1])?;
+        drop(log);
+
+        let path = dir.path().join("raft-metadata");
+        let good = std::fs::read(&path)?;
+        let mut corrupt = good.clone();
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&path, &corrupt)?;
+        assert!(Hybrid::open_from_dir_path(dir.path(), true).is_err());
+
+        // A complete temp copy left behind by an interrupted rename is used instead.
+        std::fs::write(dir.path().join("raft-metadata.tmp"), &good)?;
+        let log = Hybrid::open_from_dir_path(dir.path(), true)?;
+        assert_eq!(log.get_metadata(b"term")?, Some(vec![1]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_without_checksum() -> Result<()> {
+        // Metadata files used to be a bare bincode-encoded HashMap.
+        let dir = tempfile::tempdir()?;
+        let metadata = std::collections::HashMap::from([(b"term".to_vec(), vec![1u8])]);
+        std::fs::write(
+            dir.path().join("raft-metadata"),
+            bincode::serialize(&metadata)?,
+        )?;
+
+        let mut log = Hybrid::open_from_dir_path(dir.path(), true)?;
+        assert_eq!(log.get_metadata(b"term")?, Some(vec![1]));
+        log.set_metadata(b"vote".to_vec(), vec![2])?;
+        drop(log);
+
+        // The next write migrates it to the checksummed format.
+        let buf = std::fs::read(dir.path().join("raft-metadata"))?;
+        assert_eq!(buf[..4], crc32fast::hash(&buf[4..]).to_be_bytes());
+        let log = Hybrid::open_from_dir_path(dir.path(), true)?;
+        assert_eq!(log.get_metadata(b"term")?, Some(vec![1]));
+        assert_eq!(log.get_metadata(b"vote")?, Some(vec![2]));
+        Ok(())
+    }
+
+    fn scan_entries(log: &Hybrid<File>, range: impl RangeBounds<u64>) -> Result<Vec<Bytes>> {
+        log.scan(Range::from(range)).collect()
+    }
+
+    #[test]
+    fn test_scan_uncommitted_boundary() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut log = Hybrid::open_from_dir_path(dir.path(), false)?;
+        let entries = (1..=6u8).map(|i| Bytes::from(vec![i])).collect::<Vec<_>>();
+        for entry in &entries {
+            log.append(entry.clone())?;
+        }
+        log.commit(3)?;
+
+        // Start in the committed entries.
+        assert_eq!(scan_entries(&log, 2..=3)?, entries[1..3]);
+        assert_eq!(scan_entries(&log, 2..)?, entries[1..]);
+        // Start at the first uncommitted entry.
+        assert_eq!(scan_entries(&log, 4..)?, entries[3..]);
+        assert_eq!(scan_entries(&log, 4..=4)?, entries[3..4]);
+        assert_eq!(scan_entries(&log, 4..=5)?, entries[3..5]);
+        // Spanning committed and uncommitted entries.
+        assert_eq!(scan_entries(&log, 3..=5)?, entries[2..5]);
+        assert_eq!(scan_entries(&log, ..)?, entries);
+        // Starting within the uncommitted entries.
+        assert_eq!(scan_entries(&log, 5..)?, entries[4..]);
+        // Starting beyond the end of the log.
+        assert!(scan_entries(&log, 7..)?.is_empty());
+        assert!(scan_entries(&log, 10..=12)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut log = Hybrid::open_from_dir_path(dir.path(), true)?;
+        for i in 1..=5u8 {
+            log.append(Bytes::from(vec![i]))?;
+        }
+        log.commit(3)?;
+        log.set_metadata(b"term".to_vec(), vec![2])?;
+
+        log.reset()?;
+        assert_eq!(log.len(), 0);
+        assert_eq!(log.committed(), 0);
+        assert_eq!(log.get(1)?, None);
+        assert_eq!(std::fs::metadata(dir.path().join("raft-log"))?.len(), 0);
+        assert_eq!(log.get_metadata(b"term")?, Some(vec![2]));
+
+        // The log is usable afterwards, and the reset survives a reopen.
+        log.append(Bytes::from(vec![9]))?;
+        log.commit(1)?;
+        drop(log);
+        let log = Hybrid::open_from_dir_path(dir.path(), true)?;
+        assert_eq!(log.len(), 1);
+        assert_eq!(log.get(1)?, Some(Bytes::from(vec![9])));
+        assert_eq!(log.get_metadata(b"term")?, Some(vec![2]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_peek_uncommitted() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut log = Hybrid::open_from_dir_path(dir.path(), false)?;
+        let entries = (1..=3u8).map(|i| Bytes::from(vec![i])).collect::<Vec<_>>();
+        for entry in &entries {
+            log.append(entry.clone())?;
+        }
+        assert_eq!(log.uncommitted_len(), 3);
+        for (n, entry) in entries.iter().enumerate() {
+            assert_eq!(log.peek_uncommitted(n), Some(entry));
+        }
+        assert_eq!(log.peek_uncommitted(3), None);
+
+        log.commit(2)?;
+        assert_eq!(log.uncommitted_len(), 1);
+        assert_eq!(log.peek_uncommitted(0), Some(&entries[2]));
+        assert_eq!(log.peek_uncommitted(1), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut log = Hybrid::open_from_dir_path(dir.path(), false)?;
+        log.sync()?;
+        for i in 1..=3u8 {
+            log.append(Bytes::from(vec![i]))?;
+        }
+        log.commit(2)?;
+        log.set_metadata(b"term".to_vec(), vec![1])?;
+        log.sync()?;
+        drop(log);
+
+        let log = Hybrid::open_from_dir_path(dir.path(), false)?;
+        assert_eq!(log.committed(), 2);
+        assert_eq!(log.get(2)?, Some(Bytes::from(vec![2])));
+        assert_eq!(log.get_metadata(b"term")?, Some(vec![1]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_returning_offsets() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut log = Hybrid::open_from_dir_path(dir.path(), false)?;
+        for i in 1..=5u8 {
+            log.append(Bytes::from(vec![i; i as usize]))?;
+        }
+        assert_eq!(log.commit_returning_offsets(1)?, vec![(1, 4)]);
+        let offsets = log.commit_returning_offsets(4)?;
+        assert_eq!(
+            offsets.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+        for (i, offset) in offsets {
+            assert_eq!(log.index.get(&i).map(|(pos, _)| *pos), Some(offset));
+        }
+        assert_eq!(log.commit_returning_offsets(4)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_metadata() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut log = Hybrid::open_from_dir_path(dir.path(), false)?;
+        for key in ["peer/3", "term", "peer/1", "vote", "peer/2"] {
+            log.set_metadata(key.as_bytes().to_vec(), key.as_bytes().to_vec())?;
+        }
+        drop(log);
+
+        let log = Hybrid::open_from_dir_path(dir.path(), false)?;
+        let keys = log
+            .scan_metadata(kv::Range::from(b"peer/".to_vec()..b"peer0".to_vec()))
+            .map(|(k, v)| {
+                assert_eq!(k, v);
+                k.to_vec()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![b"peer/1".to_vec(), b"peer/2".to_vec(), b"peer/3".to_vec()]
+        );
+        assert_eq!(log.scan_metadata(kv::Range::from(..)).count(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_namespaces() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut log = Hybrid::open_from_dir_path(dir.path(), false)?;
+        log.set_metadata(b"term".to_vec(), vec![1])?;
+        log.set_metadata_ns("raft", b"term".to_vec(), vec![2])?;
+        log.set_metadata_ns("app", b"term".to_vec(), vec![3])?;
+        // A namespace that's a prefix of another mustn't overlap with it either.
+        log.set_metadata_ns("ra", b"fterm".to_vec(), vec![4])?;
+        drop(log);
+
+        let log = Hybrid::open_from_dir_path(dir.path(), false)?;
+        assert_eq!(log.get_metadata(b"term")?, Some(vec![1]));
+        assert_eq!(
+            log.get_metadata_ns(DEFAULT_NAMESPACE, b"term")?,
+            Some(vec![1])
+        );
+        assert_eq!(log.get_metadata_ns("raft", b"term")?, Some(vec![2]));
+        assert_eq!(log.get_metadata_ns("app", b"term")?, Some(vec![3]));
+        assert_eq!(log.get_metadata_ns("ra", b"fterm")?, Some(vec![4]));
+        assert_eq!(log.get_metadata_ns("raft", b"vote")?, None);
+        assert_eq!(log.scan_metadata(kv::Range::from(..)).count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_written_before_namespaces() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let metadata = BTreeMap::from([(b"term".to_vec(), vec![1u8]), (b"vote".to_vec(), vec![2])]);
+        let payload = bincode::serialize(&metadata)?;
+        let mut buf = crc32fast::hash(&payload).to_be_bytes().to_vec();
+        buf.extend_from_slice(&payload);
+        std::fs::write(dir.path().join("raft-metadata"), buf)?;
+
+        let mut log = Hybrid::open_from_dir_path(dir.path(), false)?;
+        assert_eq!(log.get_metadata(b"term")?, Some(vec![1]));
+        assert_eq!(log.get_metadata(b"vote")?, Some(vec![2]));
+        assert_eq!(log.get_metadata_ns("raft", b"term")?, None);
+
+        // The keys are kept as they are when the file is next written.
+        log.set_metadata_ns("raft", b"term".to_vec(), vec![3])?;
+        drop(log);
+        let log = Hybrid::open_from_dir_path(dir.path(), false)?;
+        assert_eq!(log.get_metadata(b"term")?, Some(vec![1]));
+        assert_eq!(log.get_metadata_ns("raft", b"term")?, Some(vec![3]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_scan_seek() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut log = Hybrid::open_from_dir_path(dir.path(), false)?;
+        let entries = (1..=8u8)
+            .map(|i| Bytes::from(vec![i; i as usize]))
+            .collect::<Vec<_>>();
+        for entry in &entries {
+            log.append(entry.clone())?;
+        }
+        log.commit(5)?;
+
+        let mut scan = log.log_scan(Range::from(2..=7));
+        assert_eq!(scan.next().transpose()?, Some(entries[1].clone()));
+        assert_eq!(scan.next().transpose()?, Some(entries[2].clone()));
+        // Forward, into the uncommitted entries.
+        scan.seek(6);
+        assert_eq!(scan.next().transpose()?, Some(entries[5].clone()));
+        // Backward, into the committed entries again.
+        scan.seek(3);
+        assert_eq!(scan.next().transpose()?, Some(entries[2].clone()));
+        // Before the start of the range, which clamps to the start.
+        scan.seek(1);
+        assert_eq!(scan.next().transpose()?, Some(entries[1].clone()));
+        scan.seek(4);
+        assert_eq!(scan.collect::<Result<Vec<_>>>()?, entries[3..7].to_vec());
+
+        let mut scan = log.log_scan(Range::from(..));
+        scan.seek(9);
+        assert!(scan.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_sync() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let log = Arc::new(Mutex::new(Hybrid::open_from_dir_path(dir.path(), false)?));
+        let group_sync = log.lock().unwrap().group_sync();
+
+        // Hold off syncs as if one were in progress, until every thread is waiting.
+        group_sync.state.lock().unwrap().in_progress = true;
+        let threads = (0..16u8)
+            .map(|i| {
+                let (log, group_sync) = (log.clone(), group_sync.clone());
+                std::thread::spawn(move || -> Result<()> {
+                    let mut log = log.lock().unwrap();
+                    let index = log.append(Bytes::from(vec![i]))?;
+                    log.commit(index)?;
+                    drop(log);
+                    group_sync.sync()
+                })
+            })
+            .collect::<Vec<_>>();
+        while group_sync.state.lock().unwrap().requested < 16 {
+            std::thread::yield_now();
+        }
+        group_sync.state.lock().unwrap().in_progress = false;
+        group_sync.synced.notify_all();
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.committed(), 16);
+        assert_eq!(log.fsync_count(), 1);
+        Ok(())
+    }
+}
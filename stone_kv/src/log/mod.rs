@@ -37,6 +37,12 @@ pub trait LogStore: Sync + Send {
     /// Sets a metadata value.
     fn set_metadata(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
 
+    /// Forces committed entries and metadata to durable storage, regardless of any sync
+    /// setting used by commit.
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     /// Returns true if the log has no entries.
     fn is_empty(&self) -> bool {
         self.len() == 0
@@ -0,0 +1,64 @@
+//! Checks that skiplist operations meant to be allocation-free are. These live in their own
+//! test binary, since counting allocations means replacing the global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use stone_kv::kv::{BlockArena, BytewiseComparator, Range, Skiplist};
+
+/// Counts allocations made by the current thread, so tests running in parallel don't disturb
+/// each other's counts.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+#[test]
+fn test_overwrite_same_length() {
+    let skiplist = Skiplist::new(BytewiseComparator::default(), BlockArena::default());
+    for i in 0..10 {
+        skiplist.insert(&[i], &[i; 16]);
+    }
+    let before = allocations();
+    skiplist.insert(&[5], &[0xff; 16]);
+    assert_eq!(allocations(), before);
+    assert_eq!(skiplist.get_entry(&[5]), Some((vec![5], vec![0xff; 16])));
+}
+
+#[test]
+fn test_for_each() {
+    let skiplist = Skiplist::new(BytewiseComparator::default(), BlockArena::default());
+    for i in 0..100u8 {
+        skiplist.insert(&[i], &[i; 4]);
+    }
+    let range = Range::from(vec![10]..vec![20]);
+    let mut keys = 0;
+    let before = allocations();
+    skiplist
+        .for_each(&range, |_, _| {
+            keys += 1;
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+    assert_eq!(allocations(), before);
+    assert_eq!(keys, 10);
+}
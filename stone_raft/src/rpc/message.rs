@@ -29,6 +29,17 @@ pub struct Message {
     pub event: Event,
 }
 
+impl Message {
+    /// Returns the client request or response ID carried by the message, so that pipelined
+    /// requests can be routed without matching on the event. Other events carry no ID.
+    pub fn id(&self) -> Option<&[u8]> {
+        match &self.event {
+            Event::ClientRequest { id, .. } | Event::ClientResponse { id, .. } => Some(id),
+            _ => None,
+        }
+    }
+}
+
 /// An event contained within messages.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Event {
@@ -103,3 +114,48 @@ pub enum Response {
     State(Vec<u8>),
     Status(Status),
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_id_round_trip() {
+        let messages = vec![
+            Message {
+                term: 1,
+                from: Address::Client,
+                to: Address::Local,
+                event: Event::ClientRequest {
+                    id: vec![0x01, 0x02],
+                    request: Request::Query(vec![0xaf]),
+                },
+            },
+            Message {
+                term: 1,
+                from: Address::Local,
+                to: Address::Client,
+                event: Event::ClientResponse {
+                    id: vec![0x03],
+                    response: Ok(Response::State(vec![0xbf])),
+                },
+            },
+            Message {
+                term: 2,
+                from: Address::Peer("a".into()),
+                to: Address::Peers,
+                event: Event::Heartbeat {
+                    commit_index: 3,
+                    commit_term: 1,
+                },
+            },
+        ];
+        let ids: Vec<Option<&[u8]>> = vec![Some(&[0x01, 0x02]), Some(&[0x03]), None];
+        for (message, id) in messages.into_iter().zip(ids) {
+            let decoded: Message =
+                bincode::deserialize(&bincode::serialize(&message).unwrap()).unwrap();
+            assert_eq!(decoded.id(), id);
+            assert_eq!(decoded, message);
+        }
+    }
+}